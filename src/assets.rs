@@ -0,0 +1,166 @@
+use crate::svg::hex_digest;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+
+/// Writes a rendered diagram to the assets directory and returns its path within it.
+///
+/// The file is named `<stem>.<extension>` if a stem is given, and by a hash of its contents otherwise.
+/// Either way, an existing file with the same contents is never rewritten.
+/// This keeps `mdbook serve` from seeing a change and rebuilding forever.
+pub fn write_asset(
+    dir: &Path,
+    stem: Option<&str>,
+    contents: &[u8],
+    extension: &str,
+) -> Result<String> {
+    let name = asset_name(stem, &hex_digest(contents), extension);
+    let path = dir.join(&name);
+    let unchanged = match stem {
+        Some(_) => std::fs::read(&path).is_ok_and(|existing| existing == contents),
+        None => path.exists(),
+    };
+    if !unchanged {
+        let parent = path.parent().unwrap_or(dir);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create {}", parent.display()))?;
+        std::fs::write(&path, contents)
+            .with_context(|| format!("could not write {}", path.display()))?;
+    }
+    Ok(name)
+}
+
+/// Copies a rendered diagram from a file into the assets directory and returns its path within it.
+///
+/// Files are named and rewritten like with `write_asset`, but the diagram is copied a piece at a time instead
+/// of being read into memory whole.
+pub fn copy_asset(
+    dir: &Path,
+    stem: Option<&str>,
+    source: &Path,
+    extension: &str,
+) -> Result<String> {
+    let digest = file_digest(source)?;
+    let name = asset_name(stem, &digest, extension);
+    let path = dir.join(&name);
+    let unchanged = match stem {
+        Some(_) => file_digest(&path).is_ok_and(|existing| existing == digest),
+        None => path.exists(),
+    };
+    if !unchanged {
+        let parent = path.parent().unwrap_or(dir);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create {}", parent.display()))?;
+        // Not `fs::copy`, which would carry over the cache file's owner-only permissions.
+        let mut reader = std::fs::File::open(source)
+            .with_context(|| format!("could not read {}", source.display()))?;
+        let mut writer = std::fs::File::create(&path)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        std::io::copy(&mut reader, &mut writer)
+            .with_context(|| format!("could not write {}", path.display()))?;
+    }
+    Ok(name)
+}
+
+/// Name of an asset file: `<stem>.<extension>` if there's a stem, and named by its digest otherwise.
+pub fn asset_name(stem: Option<&str>, digest: &str, extension: &str) -> String {
+    match stem {
+        Some(stem) => format!("{stem}.{extension}"),
+        None => format!("{}.{extension}", &digest[..16]),
+    }
+}
+
+/// Hex SHA-256 digest of a file, read a piece at a time.
+pub fn file_digest(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// File stem for a diagram in the `mirror` asset layout: `<chapter path without extension>/diagram-<n>`.
+pub fn mirror_stem(chapter_path: &Path, index: usize) -> String {
+    let mut stem = String::new();
+    for component in chapter_path.with_extension("").components() {
+        stem.push_str(&component.as_os_str().to_string_lossy());
+        stem.push('/');
+    }
+    stem.push_str(&format!("diagram-{}", index + 1));
+    stem
+}
+
+/// URL of an asset relative to the page rendered from a chapter.
+///
+/// `assets_dir` and `chapter_path` are both relative to the sources root. Chapters with no source path
+/// (like generated ones) don't have a known location, so they link from `site_base_url` instead.
+pub fn relative_url(
+    chapter_path: Option<&Path>,
+    site_base_url: Option<&str>,
+    assets_dir: &Path,
+    name: &str,
+) -> Result<String> {
+    let mut url = match (chapter_path, site_base_url) {
+        (Some(chapter_path), _) => {
+            "../".repeat(chapter_path.components().count().saturating_sub(1))
+        }
+        (None, Some(base)) => format!("{}/", base.trim_end_matches('/')),
+        (None, None) => bail!(
+            "cannot link to diagram files from chapters with no source path; set site_base_url to link to them from the site root"
+        ),
+    };
+    for component in assets_dir.components() {
+        url.push_str(&component.as_os_str().to_string_lossy());
+        url.push('/');
+    }
+    url.push_str(name);
+    Ok(url)
+}
+
+/// File in the assets directory listing the files the preprocessor wrote there, so other files are left alone.
+const MANIFEST: &str = ".kroki-manifest";
+
+/// Files that the preprocessor wrote to the assets directory in earlier builds but that aren't in `used`, as
+/// paths within it. Files it didn't write, like ones of your own, are never listed.
+pub fn unused_assets(dir: &Path, used: &HashSet<String>) -> Vec<String> {
+    let manifest = std::fs::read_to_string(dir.join(MANIFEST)).unwrap_or_default();
+    let mut unused = manifest
+        .lines()
+        .filter(|name| !name.is_empty() && !used.contains(*name) && dir.join(name).is_file())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    unused.sort();
+    unused
+}
+
+/// Records the files that the preprocessor has written to the assets directory, for `unused_assets` in later builds.
+///
+/// Like asset files, the manifest is only rewritten if it changed.
+pub fn write_manifest(dir: &Path, names: &HashSet<String>) -> Result<()> {
+    let mut names = names.iter().map(String::as_str).collect::<Vec<_>>();
+    names.sort();
+    let mut manifest = names.join("\n");
+    manifest.push('\n');
+    let path = dir.join(MANIFEST);
+    if std::fs::read_to_string(&path).is_ok_and(|existing| existing == manifest) {
+        return Ok(());
+    }
+    std::fs::write(&path, manifest).with_context(|| format!("could not write {}", path.display()))
+}
+
+/// Deletes unused asset files, along with any directories that are left empty.
+pub fn prune_assets(dir: &Path, unused: &[String]) -> Result<()> {
+    for name in unused {
+        let path = dir.join(name);
+        std::fs::remove_file(&path)
+            .with_context(|| format!("could not delete {}", path.display()))?;
+        let mut parent = path.parent();
+        while let Some(empty) = parent.filter(|p| *p != dir) {
+            if std::fs::remove_dir(empty).is_err() {
+                break;
+            }
+            parent = empty.parent();
+        }
+    }
+    Ok(())
+}
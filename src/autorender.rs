@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Finds files under the sources root with one of the given extensions, as paths relative to it.
+///
+/// Extensions may be written with or without their leading dot. The assets directory is skipped.
+pub fn find_files(source: &Path, extensions: &[String], skip: &Path) -> Result<Vec<PathBuf>> {
+    let extensions = extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.'))
+        .collect::<Vec<_>>();
+    let mut files = Vec::new();
+    let mut dirs = vec![source.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if dir == skip {
+            continue;
+        }
+        let entries =
+            std::fs::read_dir(&dir).with_context(|| format!("could not read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| extensions.contains(&e))
+            {
+                files.push(path.strip_prefix(source)?.to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The diagram type for a file extension, which is the extension itself unless it's a common alias.
+pub fn diagram_type_for(path: &Path) -> String {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "puml" | "iuml" | "pu" => "plantuml",
+        "mmd" => "mermaid",
+        "dot" | "gv" => "graphviz",
+        "bob" => "svgbob",
+        "dsl" => "structurizr",
+        other => other,
+    }
+    .to_string()
+}
@@ -0,0 +1,122 @@
+use crate::diagram::KrokiRequestBody;
+use crate::svg::hex_digest;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use std::io::Write;
+use std::path::PathBuf;
+use tempfile::{NamedTempFile, TempDir};
+
+/// Where a build keeps kroki's responses on disk, so they aren't held in memory for the whole build.
+///
+/// With `cache = true` that's the cache directory, whose entries are keyed by everything that affects kroki's
+/// output, so they never go stale. Otherwise it's a temporary directory that's deleted after the build.
+pub struct Cache {
+    dir: PathBuf,
+    /// Keeps the temporary directory alive until the build is over, if there's no cache directory.
+    _temp: Option<TempDir>,
+}
+
+impl Cache {
+    /// A cache in `dir` that's kept between builds.
+    pub fn persistent(dir: PathBuf) -> Self {
+        Cache { dir, _temp: None }
+    }
+
+    /// A cache in a new temporary directory that's deleted when it's dropped.
+    pub fn temporary() -> Result<Self> {
+        let temp = tempfile::Builder::new()
+            .prefix("mdbook-kroki-")
+            .tempdir()
+            .context("could not create a temporary directory for responses")?;
+        Ok(Cache {
+            dir: temp.path().to_path_buf(),
+            _temp: Some(temp),
+        })
+    }
+
+    /// Key for a request: a hash of the endpoint, the body (including its output format), and the headers.
+    pub fn key(endpoint: &str, body: &KrokiRequestBody, headers: &HeaderMap) -> String {
+        let mut headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), String::from_utf8_lossy(value.as_bytes())))
+            .collect::<Vec<_>>();
+        headers.sort();
+        let request = serde_json::json!({
+            "endpoint": endpoint,
+            "body": body,
+            "headers": headers,
+        });
+        hex_digest(request.to_string().as_bytes())
+    }
+
+    /// The path of the output for a key, if there is one.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.dir.join(key);
+        path.is_file().then_some(path)
+    }
+
+    /// Stores the output for a key, and returns its path.
+    pub fn put(&self, key: &str, contents: &[u8]) -> Result<PathBuf> {
+        let mut temp = self.temp_file()?;
+        temp.write_all(contents)
+            .with_context(|| format!("could not write {}", temp.path().display()))?;
+        self.persist(key, temp)
+    }
+
+    /// Stores the output for a key as it's read from a kroki response, and returns its path. The response is
+    /// never held in memory whole.
+    pub async fn put_stream(&self, key: &str, mut response: reqwest::Response) -> Result<PathBuf> {
+        let mut temp = self.temp_file()?;
+        while let Some(chunk) = response.chunk().await? {
+            temp.write_all(&chunk)
+                .with_context(|| format!("could not write {}", temp.path().display()))?;
+        }
+        self.persist(key, temp)
+    }
+
+    /// A file to write an entry to before it's moved into place, so a concurrent build never reads half of one.
+    fn temp_file(&self) -> Result<NamedTempFile> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create {}", self.dir.display()))?;
+        NamedTempFile::new_in(&self.dir)
+            .with_context(|| format!("could not create a file in {}", self.dir.display()))
+    }
+
+    fn persist(&self, key: &str, temp: NamedTempFile) -> Result<PathBuf> {
+        let path = self.dir.join(key);
+        temp.persist(&path)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_get_their_own_entries() {
+        let cache = Cache::temporary().unwrap();
+        let body = |output_format: &str| KrokiRequestBody {
+            diagram_source: "a -> b".to_string(),
+            diagram_type: "graphviz".to_string(),
+            output_format: output_format.to_string(),
+        };
+        let headers = HeaderMap::new();
+        let svg_key = Cache::key("https://kroki.io/", &body("svg"), &headers);
+        let png_key = Cache::key("https://kroki.io/", &body("png"), &headers);
+        assert_ne!(svg_key, png_key);
+
+        cache.put(&svg_key, b"<svg/>").unwrap();
+        cache.put(&png_key, b"\x89PNG").unwrap();
+        assert_eq!(
+            std::fs::read(cache.get(&svg_key).unwrap()).unwrap(),
+            b"<svg/>"
+        );
+        assert_eq!(
+            std::fs::read(cache.get(&png_key).unwrap()).unwrap(),
+            b"\x89PNG"
+        );
+        assert_eq!(std::fs::read_dir(&cache.dir).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::{bail, Result};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+
+/// Output formats a kroki deployment supports, by diagram type.
+pub struct Capabilities(HashMap<String, Vec<String>>);
+
+impl Capabilities {
+    /// Fetches a deployment's capabilities, a JSON object of diagram types to lists of output formats.
+    ///
+    /// Returns `None` if they aren't available, so formats go unchecked.
+    pub async fn fetch(client: &reqwest::Client, url: &str, headers: HeaderMap) -> Option<Self> {
+        let response = match client.get(url).headers(headers).send().await {
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                log::info!(
+                    "{url} doesn't list kroki's capabilities, so output formats won't be checked"
+                );
+                return None;
+            }
+            Ok(response) => response,
+            Err(error) => {
+                log::warn!("could not fetch kroki's capabilities from {url}, so output formats won't be checked: {error}");
+                return None;
+            }
+        };
+        let formats = async {
+            let body = response.error_for_status()?.bytes().await?;
+            Ok::<_, anyhow::Error>(serde_json::from_slice(&body)?)
+        }
+        .await;
+        match formats {
+            Ok(formats) => Some(Capabilities(lowercase(formats))),
+            Err(error) => {
+                log::warn!("could not read kroki's capabilities from {url}, so output formats won't be checked: {error}");
+                None
+            }
+        }
+    }
+
+    /// Fails if a diagram type is listed without the given output format. Types that aren't listed pass.
+    pub fn check(&self, diagram_type: &str, format: &str) -> Result<()> {
+        let Some(formats) = self.0.get(&diagram_type.to_ascii_lowercase()) else {
+            return Ok(());
+        };
+        if !formats.iter().any(|f| f.eq_ignore_ascii_case(format)) {
+            bail!(
+                "kroki doesn't support {format} output for {diagram_type} diagrams, only {}",
+                formats.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn lowercase(formats: HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
+    formats
+        .into_iter()
+        .map(|(diagram_type, formats)| (diagram_type.to_ascii_lowercase(), formats))
+        .collect()
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+/// Limits how many kroki requests are in flight at once.
+pub struct Concurrency {
+    semaphore: Semaphore,
+    max: usize,
+    /// Permits added to the semaphore so far, which only grows toward `max` when ramping up.
+    permits: AtomicUsize,
+}
+
+impl Concurrency {
+    /// Allows `max` requests at once, or when `ramp_up` is on, starts with one and adds one per success.
+    pub fn new(max: usize, ramp_up: bool) -> Self {
+        let initial = if ramp_up { 1 } else { max };
+        Concurrency {
+            semaphore: Semaphore::new(initial),
+            max,
+            permits: AtomicUsize::new(initial),
+        }
+    }
+
+    /// Waits for a request slot, which is freed when the permit is dropped.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.semaphore
+            .acquire()
+            .await
+            .expect("request semaphore is never closed")
+    }
+
+    /// Records a successful request, adding a slot if the limit is still ramping up.
+    pub fn succeeded(&self) {
+        let grown = self
+            .permits
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |permits| {
+                (permits < self.max).then_some(permits + 1)
+            })
+            .is_ok();
+        if grown {
+            self.semaphore.add_permits(1);
+        }
+    }
+}
+
+/// Separate limits on how many requests for each diagram type are in flight at once,
+/// so a few heavy diagram types can't take every request slot.
+pub struct TypeLimits {
+    limits: HashMap<String, usize>,
+    default: Option<usize>,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl TypeLimits {
+    /// Limits each type in `limits`, and every other type to `default` if there is one.
+    pub fn new(limits: &HashMap<String, usize>, default: Option<usize>) -> Self {
+        TypeLimits {
+            limits: limits
+                .iter()
+                .map(|(diagram_type, limit)| (diagram_type.to_ascii_lowercase(), *limit))
+                .collect(),
+            default,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a request slot for a diagram type, or returns `None` right away if the type isn't limited.
+    pub async fn acquire(&self, diagram_type: &str) -> Option<OwnedSemaphorePermit> {
+        let diagram_type = diagram_type.to_ascii_lowercase();
+        let limit = self
+            .limits
+            .get(&diagram_type)
+            .copied()
+            .or(self.default)
+            .filter(|&limit| limit > 0)?;
+        let semaphore = self
+            .semaphores
+            .lock()
+            .expect("type semaphores lock poisoned")
+            .entry(diagram_type)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        Some(
+            semaphore
+                .acquire_owned()
+                .await
+                .expect("type semaphore is never closed"),
+        )
+    }
+}
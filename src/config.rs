@@ -0,0 +1,820 @@
+use crate::types;
+use anyhow::{bail, Context, Result};
+use mdbook::preprocess::PreprocessorContext;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Preprocessor options, read from the `[preprocessor.kroki-preprocessor]` table in `book.toml`.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Leave every diagram as-is without rendering it. Overridden by the `KROKI_SKIP` environment variable.
+    pub skip_rendering: bool,
+
+    /// Leave diagrams in chapters with no source path, like drafts, as they are.
+    pub skip_drafts: bool,
+
+    /// Render every diagram to check that it works, but leave the book unchanged and write no files. Any
+    /// failure fails the build. Overridden by the `KROKI_VALIDATE_ONLY` environment variable.
+    pub validate_only: bool,
+
+    /// Log progress at info level: the diagrams found in each chapter, how long each took, and a summary at the end.
+    pub verbose: bool,
+
+    /// Kroki endpoint, or the `KROKI_ENDPOINT` environment variable if it isn't set. Always ends with a trailing
+    /// slash once loaded.
+    pub endpoint: String,
+
+    /// Other names for diagram types, like `puml = "plantuml"`, optionally with default attributes for diagrams
+    /// that use them. Also accepted as `aliases`.
+    #[serde(alias = "aliases")]
+    pub type_aliases: HashMap<String, TypeAlias>,
+
+    /// Languages of plain fenced code blocks to render as diagrams of that type, like `mermaid` for ```` ```mermaid ````.
+    pub fenced_types: Vec<String>,
+
+    /// Endpoints that replace `endpoint` when building with specific mdbook renderers.
+    pub renderer_endpoints: HashMap<String, String>,
+
+    /// Endpoints for specific diagram types, keyed by type. Each ends with a trailing slash once loaded.
+    pub endpoints: HashMap<String, String>,
+
+    /// Endpoints for specific diagram types from `KROKI_ENDPOINT_<TYPE>` environment variables.
+    #[serde(skip)]
+    pub env_endpoints: HashMap<String, String>,
+
+    /// URL of a JSON object listing the output formats kroki supports for each diagram type.
+    ///
+    /// When it's set, formats are checked before requests to `endpoint` are sent. Relative to `endpoint`.
+    pub capabilities_url: Option<String>,
+
+    /// The mdbook renderer the book is being built for, like `html`.
+    #[serde(skip)]
+    pub renderer: String,
+
+    /// The mdbook renderers diagrams are rendered for. This is mdbook's own `preprocessor.<name>.renderers` key,
+    /// which mdbook reads itself, and only asks the preprocessor about renderers when it isn't set.
+    pub renderers: Vec<String>,
+
+    /// Type of diagrams that don't give one, like ```` ```kroki ```` blocks and `<kroki>` tags without a `type`.
+    pub default_diagram_type: Option<String>,
+
+    /// Format diagrams are rendered in, unless `renderer_formats` or a diagram's `format` attribute says otherwise.
+    #[serde(alias = "default_output_format")]
+    pub output_format: String,
+
+    /// Formats for specific diagram types when building with specific mdbook renderers, keyed by renderer
+    /// then diagram type. A `default` key sets the format for every other type with that renderer.
+    pub renderer_formats: HashMap<String, HashMap<String, String>>,
+
+    /// Headers sent with every request to kroki.
+    pub headers: HashMap<String, String>,
+
+    /// Headers sent with requests for specific diagram types, overriding `headers`.
+    pub type_headers: HashMap<String, HashMap<String, String>>,
+
+    /// Kroki diagram options for a second variant of every diagram that's only shown when printing.
+    pub print_variant: Option<HashMap<String, String>>,
+
+    /// Render a second variant of every SVG diagram with `dark_options`, shown instead with mdbook's dark themes.
+    pub theme_aware: bool,
+
+    /// Kroki diagram options for the dark variant of diagrams with `theme_aware`.
+    pub dark_options: HashMap<String, String>,
+
+    /// How diagrams are sent to kroki: in a POST body, or encoded into the URL of a GET request.
+    pub method: RequestMethod,
+
+    /// JSON that request bodies are wrapped in, for gateways in front of kroki. `"$body"` marks where the body goes.
+    pub request_envelope: Option<serde_json::Value>,
+
+    /// JSON pointer to the rendered diagram in responses, for gateways that wrap kroki's output.
+    pub response_pointer: Option<String>,
+
+    /// JSON pointer to the SVG in responses with a JSON content type, for backends that wrap SVGs in JSON. Only used
+    /// when `response_pointer` isn't set.
+    pub json_response_pointer: String,
+
+    /// Most times to retry a kroki request that failed in a way that might be temporary.
+    pub retries: u32,
+
+    /// Milliseconds to wait before the first retry of a request, doubled for each retry after.
+    pub retry_backoff_ms: u64,
+
+    /// Response statuses that are retried, like `[502, 503, 520]`. Any 5xx status and 429 if unset.
+    pub retry_statuses: Option<Vec<u16>>,
+
+    /// Most retries across the whole build. Once they're used up, failures are final.
+    pub retry_budget: Option<usize>,
+
+    /// Seconds to wait for each kroki request before giving up on it. Zero waits forever.
+    pub timeout_seconds: u64,
+
+    /// Most kroki requests in flight at once. Unlimited if unset or zero.
+    pub max_concurrency: Option<usize>,
+
+    /// Most requests in flight at once for specific diagram types, on top of `max_concurrency`.
+    pub type_concurrency: HashMap<String, usize>,
+
+    /// Most requests in flight at once for each diagram type that isn't in `type_concurrency`.
+    pub default_type_concurrency: Option<usize>,
+
+    /// Start with one request in flight and allow one more for each success, up to `max_concurrency`.
+    ///
+    /// This is gentler on a cold server, like a JVM-based kroki that was just started.
+    pub ramp_up: bool,
+
+    /// Where diagrams are rendered: by a kroki server, or by `local_command`.
+    pub mode: Mode,
+
+    /// Command that renders diagrams with `mode = "local"`, as a program and its arguments. `{type}` and `{format}`
+    /// in the arguments are replaced with each diagram's type and output format.
+    pub local_command: Vec<String>,
+
+    /// Render graphviz diagrams with a local `dot` command instead of kroki. Requires the `local-graphviz`
+    /// feature.
+    pub local_graphviz: bool,
+
+    /// The `dot` command used by `local_graphviz`.
+    pub dot_command: PathBuf,
+
+    /// Keep rendered diagrams in `cache_dir`, so unchanged diagrams aren't sent to kroki again. On by default.
+    pub cache: bool,
+
+    /// Where rendered diagrams are cached, relative to the book root.
+    pub cache_dir: PathBuf,
+
+    /// Label diagrams without a `tooltip`, `alt`, or `caption` with their type, like "plantuml diagram".
+    pub alt_from_type: bool,
+
+    /// Add visually hidden text after each diagram from its `tooltip`, `alt`, `caption`, and `keywords`
+    /// attributes, so mdbook's search can find it.
+    pub search_text: bool,
+
+    /// Whether diagrams are inlined into chapters or written to separate files.
+    pub output: Output,
+
+    /// Wrap each diagram in a container sized to its `viewBox`, so the page doesn't shift while it loads.
+    pub reserve_space: bool,
+
+    /// Wrap inlined SVGs in a `wrapper_tag`.
+    pub wrap_svg: bool,
+
+    /// The element inlined SVGs are wrapped in.
+    pub wrapper_tag: WrapperTag,
+
+    /// Class given to the wrapper element, for styling diagrams.
+    pub wrapper_class: Option<String>,
+
+    /// Strip the fixed size from inlined SVGs and wrap them in a `<div>` no wider than the page, so they scale down.
+    pub responsive: bool,
+
+    /// Diagram types whose inlined SVGs are wrapped in a `wrapper_tag` even when `wrap_svg` is off.
+    pub wrap_svg_types: Vec<String>,
+
+    /// With `output = "auto-object"`, how big an interactive SVG can be before it's embedded with an `<object>` tag.
+    pub auto_object_bytes: usize,
+
+    /// With `output = "auto-object"`, text that marks an SVG as interactive, like links and scripts.
+    pub auto_object_markers: Vec<String>,
+
+    /// Separate diagrams that have their lines to themselves from the markdown right above and below them
+    /// with blank lines, so they don't run into a neighbouring heading, list, or paragraph. On by default.
+    pub pad_blank_lines: bool,
+
+    /// Where diagram files are written in file mode, relative to the sources root.
+    pub assets_dir: PathBuf,
+
+    /// URL prefix to link diagram files from instead of relative paths, like a CDN that hosts the assets directory.
+    pub asset_url_prefix: Option<String>,
+
+    /// How diagram files are named in the assets directory.
+    pub asset_layout: AssetLayout,
+
+    /// Delete files in the assets directory that no diagram uses anymore, instead of just listing them.
+    pub prune_assets: bool,
+
+    /// Wrap diagram images in links to their files, so readers can open them full size. Requires file mode.
+    pub link_to_full: bool,
+
+    /// Show a shimmer in place of each diagram file until it loads. Requires file mode.
+    pub loading_placeholder: bool,
+
+    /// Base URL of the built site, used to link diagram files from chapters with no source path.
+    pub site_base_url: Option<String>,
+
+    /// Extensions of loose diagram files under the sources root to render into the assets directory,
+    /// even if no chapter references them.
+    pub autorender_extensions: Vec<String>,
+
+    /// HTML that each `{{#kroki-gallery}}` is replaced with, where `{items}` is the gallery's diagrams.
+    pub gallery_wrapper: String,
+
+    /// HTML for each diagram in a gallery, where `{diagram}` is the diagram and `{caption}` is its file name.
+    pub gallery_item: String,
+
+    /// Extra formats to render every diagram in, written to the assets directory and linked for download.
+    pub also_formats: Vec<String>,
+
+    /// Keep inline markup out of the output so it works under a strict content security policy.
+    ///
+    /// Forces file mode and strips scripts from the written SVGs.
+    pub csp_safe: bool,
+
+    /// What to do when a diagram fails to render. Diagrams can override this with an `on_error` attribute.
+    pub on_error: OnError,
+
+    /// Most diagrams that can fail before the build does. Failed diagrams are replaced by placeholders
+    /// like with `on_error = "placeholder"`, and the build fails at the end if there are too many.
+    pub max_errors: Option<usize>,
+
+    /// What to do when kroki doesn't support a diagram's type.
+    pub on_unsupported_type: OnUnsupportedType,
+
+    /// Send diagrams with types kroki isn't known to support instead of failing before rendering them.
+    pub allow_unknown_types: bool,
+
+    /// What to do when kroki's response doesn't contain an SVG.
+    pub on_missing_svg: OnMissingSvg,
+
+    /// Most bytes that inlined diagrams can add to the whole book.
+    pub total_output_budget: Option<usize>,
+
+    /// What to do when inlined diagrams go over `total_output_budget`.
+    pub on_over_budget: OnOverBudget,
+
+    /// Most bytes that a single SVG can have and still be inlined.
+    pub max_svg_bytes: Option<usize>,
+
+    /// What to do with SVGs over `max_svg_bytes`.
+    pub oversize: Oversize,
+
+    /// Reject rendered SVGs that aren't well-formed XML instead of embedding them.
+    pub validate_svg: bool,
+
+    /// Experimental: move each chapter's inlined SVGs into one sprite sheet of `<symbol>`s at its top,
+    /// referenced with `<use>` where each diagram was.
+    pub sprite_sheet: bool,
+
+    /// Move `<style>` blocks out of inlined SVGs into one scoped block per chapter.
+    pub hoist_styles: bool,
+
+    /// How diagram sources are encoded, so they can be decoded before they're sent to kroki.
+    pub source_encoding: SourceEncoding,
+
+    /// Embed local images referenced by diagrams as `data:` URIs before sending them to kroki.
+    pub embed_images: bool,
+
+    /// Inline PlantUML `!include`s of local files before sending diagrams to kroki.
+    pub plantuml_includes: bool,
+
+    /// What to do when an inlined PlantUML include doesn't exist.
+    pub on_missing_include: OnMissingInclude,
+
+    /// Wrap PlantUML sources without an `@start` line in `@startuml`/`@enduml`, so settings are always injected inside one.
+    pub plantuml_autowrap: bool,
+
+    /// Directory that `root="book"` and `root="source"` file references extend from, relative to those roots.
+    pub include_base: Option<PathBuf>,
+
+    /// File of PlantUML settings injected into every PlantUML diagram, relative to the book root.
+    pub plantuml_config: Option<PathBuf>,
+
+    /// Directory of golden files that rendered diagrams are compared against, relative to the book root.
+    pub golden_dir: Option<PathBuf>,
+
+    /// Write rendered diagrams over their goldens instead of comparing. Overridden by the `KROKI_UPDATE_GOLDENS` environment variable.
+    pub update_goldens: bool,
+
+    /// Write each request body instead of sending it to kroki.
+    pub dump_requests: DumpRequests,
+
+    /// Attributes that every diagram must have, like `alt`.
+    pub required_attributes: Vec<String>,
+
+    /// Format of diagnostics about diagrams, for tools like editor plugins.
+    pub diagnostics_format: DiagnosticsFormat,
+
+    /// Where JSON diagnostics are written, relative to the book root. Defaults to stderr.
+    pub diagnostics_file: Option<PathBuf>,
+}
+
+/// How rendered diagrams are put into chapters.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    /// Inline the SVG into the chapter.
+    Inline,
+    /// Write the SVG to a file and reference it with an `<img>` tag.
+    Files,
+    /// Inline SVGs, except interactive ones over `auto_object_bytes`, which are written to a file and
+    /// embedded with an `<object>` tag so their links and scripts still work.
+    AutoObject,
+}
+
+/// Where diagrams are rendered.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Send them to a kroki server.
+    Http,
+    /// Pipe them through `local_command`, for builds that can't reach a kroki server.
+    Local,
+}
+
+/// How diagrams are sent to kroki.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestMethod {
+    /// POST the request body as JSON.
+    Post,
+    /// GET a URL with the diagram source compressed into its path, which CDNs can cache.
+    /// Falls back to POST for diagrams too big to fit in a URL.
+    Get,
+}
+
+/// How diagram files are named in the assets directory.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetLayout {
+    /// A flat directory of files named by a hash of their contents.
+    Hash,
+    /// Directories mirroring the chapters, with files named by their position in the chapter.
+    Mirror,
+}
+
+/// How diagram sources are encoded.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceEncoding {
+    /// Sources are used as they are.
+    Plain,
+    /// Sources are percent-encoded, like in a URL.
+    Url,
+}
+
+/// What to do when an inlined PlantUML include doesn't exist.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingInclude {
+    /// Fail the diagram.
+    Fail,
+    /// Replace the include with a comment and log a warning.
+    Skip,
+}
+
+/// The element inlined SVGs are wrapped in.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapperTag {
+    /// A `<pre>`, which themes usually style like a code block.
+    Pre,
+    /// A plain `<div>`.
+    Div,
+    /// A `<figure>`, with a `<figcaption>` from the diagram's `caption` attribute if it has one.
+    Figure,
+}
+
+/// What to do when a diagram fails to render.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Fail the build.
+    Fail,
+    /// Render a placeholder with the error and log a warning, and keep building.
+    Placeholder,
+}
+
+/// What to do when kroki doesn't support a diagram's type.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnUnsupportedType {
+    /// Fail the build.
+    Fail,
+    /// Render a placeholder naming the type and log a warning.
+    Placeholder,
+}
+
+/// What to do when kroki's response doesn't contain an SVG.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnMissingSvg {
+    /// Fail the build.
+    Error,
+    /// Embed the response as escaped text in a `<pre>` tag and log a warning.
+    Passthrough,
+}
+
+/// What to do when inlined diagrams go over the book's output budget.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OnOverBudget {
+    /// Fail the build.
+    Fail,
+    /// Log a warning.
+    Warn,
+}
+
+/// What a diagram type alias stands for.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum TypeAlias {
+    /// Just another name for a type, like `puml = "plantuml"`.
+    Type(String),
+    /// A type with default attributes, like `arch = { type = "structurizr", format = "png" }`.
+    Preset {
+        #[serde(rename = "type")]
+        diagram_type: String,
+        format: Option<String>,
+        /// Kroki diagram options, sent like `option-<name>` attributes.
+        #[serde(default)]
+        options: HashMap<String, String>,
+    },
+}
+
+impl TypeAlias {
+    /// The type the alias stands for.
+    pub fn diagram_type(&self) -> &str {
+        match self {
+            TypeAlias::Type(diagram_type) | TypeAlias::Preset { diagram_type, .. } => diagram_type,
+        }
+    }
+
+    /// Default attributes for diagrams that use the alias.
+    pub fn attributes(&self) -> HashMap<String, String> {
+        let TypeAlias::Preset {
+            format, options, ..
+        } = self
+        else {
+            return HashMap::new();
+        };
+        let options = options
+            .iter()
+            .map(|(name, value)| (format!("option-{name}"), value.clone()));
+        format
+            .iter()
+            .map(|format| ("format".to_string(), format.clone()))
+            .chain(options)
+            .collect()
+    }
+}
+
+/// What to do with an SVG that's too big to inline.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Oversize {
+    /// Inline it anyway, and log a warning.
+    Warn,
+    /// Write it to a file referenced with an `<img>` tag instead, and log a warning.
+    Img,
+    /// Fail the build.
+    Fail,
+}
+
+/// Format of diagnostics about diagrams.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsFormat {
+    /// Only the usual log messages and errors.
+    Text,
+    /// Also write a line of JSON for each error and warning about a diagram.
+    Json,
+}
+
+/// Where to dump request bodies to, if anywhere.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DumpRequests {
+    /// `true` dumps to stderr.
+    Stderr(bool),
+    /// A file path, relative to the book root.
+    File(PathBuf),
+}
+
+impl Config {
+    /// Reads the config for the named preprocessor, falling back to defaults if it has no table.
+    pub fn from_context(ctx: &PreprocessorContext, name: &str) -> Result<Self> {
+        let mut config: Config = match ctx.config.get_preprocessor(name) {
+            Some(table) => toml::Value::Table(table.clone())
+                .try_into()
+                .with_context(|| format!("invalid config for preprocessor.{name}"))?,
+            None => Config::default(),
+        };
+
+        if let Some(skip) = env_flag("KROKI_SKIP") {
+            config.skip_rendering = skip;
+        }
+        if let Some(update) = env_flag("KROKI_UPDATE_GOLDENS") {
+            config.update_goldens = update;
+        }
+        if let Some(validate_only) = env_flag("KROKI_VALIDATE_ONLY") {
+            config.validate_only = validate_only;
+        }
+
+        if config.csp_safe {
+            config.output = Output::Files;
+        }
+        if config.link_to_full && config.output != Output::Files {
+            bail!(r#"link_to_full requires output = "files""#);
+        }
+        if config.loading_placeholder && config.output != Output::Files {
+            bail!(r#"loading_placeholder requires output = "files""#);
+        }
+        if config.sprite_sheet && config.output != Output::Inline {
+            bail!(r#"sprite_sheet requires output = "inline""#);
+        }
+        if config.sprite_sheet && config.hoist_styles {
+            bail!("sprite_sheet can't be combined with hoist_styles");
+        }
+        if config.method == RequestMethod::Get && config.request_envelope.is_some() {
+            bail!(r#"method = "get" can't be combined with request_envelope"#);
+        }
+        if config.ramp_up && config.max_concurrency.unwrap_or(0) == 0 {
+            bail!("ramp_up requires max_concurrency");
+        }
+        if config.local_graphviz && !cfg!(feature = "local-graphviz") {
+            bail!("local_graphviz requires building with the local-graphviz feature");
+        }
+        if config.mode == Mode::Local && config.local_command.is_empty() {
+            bail!(r#"mode = "local" requires a local_command"#);
+        }
+
+        for (name, value) in config
+            .headers
+            .iter_mut()
+            .chain(config.type_headers.values_mut().flatten())
+        {
+            *value =
+                expand_env(value).with_context(|| format!("invalid value for header `{name}`"))?;
+        }
+
+        let endpoint_set = ctx
+            .config
+            .get_preprocessor(name)
+            .is_some_and(|table| table.contains_key("endpoint"));
+        match std::env::var("KROKI_ENDPOINT") {
+            Ok(endpoint) if !endpoint_set => {
+                log::info!(
+                    "using {endpoint} from KROKI_ENDPOINT, since book.toml doesn't set an endpoint"
+                );
+                config.endpoint = endpoint;
+            }
+            Ok(_) => log::info!("KROKI_ENDPOINT is set, but book.toml's endpoint takes precedence"),
+            Err(_) => {}
+        }
+        if let Some(endpoint) = config.renderer_endpoints.get(&ctx.renderer) {
+            config.endpoint = endpoint.clone();
+        }
+        config.endpoint = with_trailing_slash(config.endpoint);
+        config.endpoints = std::mem::take(&mut config.endpoints)
+            .into_iter()
+            .map(|(diagram_type, endpoint)| {
+                (
+                    diagram_type.to_ascii_lowercase(),
+                    with_trailing_slash(endpoint),
+                )
+            })
+            .collect();
+        // Types are looked up lowercased, and tables for the same type written differently are merged.
+        let mut type_headers = HashMap::<_, HashMap<_, _>>::new();
+        for (diagram_type, headers) in std::mem::take(&mut config.type_headers) {
+            type_headers
+                .entry(diagram_type.to_ascii_lowercase())
+                .or_default()
+                .extend(headers);
+        }
+        config.type_headers = type_headers;
+        for diagram_type in &mut config.wrap_svg_types {
+            diagram_type.make_ascii_lowercase();
+        }
+        config.renderer = ctx.renderer.clone();
+
+        for (key, value) in std::env::vars() {
+            if let Some(diagram_type) = key.strip_prefix("KROKI_ENDPOINT_") {
+                let endpoint = with_trailing_slash(value);
+                log::info!(
+                    "{key} is set, so {endpoint} takes precedence over book.toml for {} diagrams",
+                    diagram_type.to_ascii_lowercase()
+                );
+                config
+                    .env_endpoints
+                    .insert(diagram_type.to_ascii_lowercase(), endpoint);
+            }
+        }
+
+        for (alias, target) in &config.type_aliases {
+            let target = target.diagram_type();
+            if !target.eq_ignore_ascii_case(alias) && config.type_alias(target).is_some() {
+                bail!("type alias `{alias}` points at `{target}`, which is another alias; aliases can't refer to aliases");
+            }
+            let target = target.to_ascii_lowercase();
+            if !config.allow_unknown_types
+                && !types::is_known(&target)
+                && config.endpoint_for(&target) == config.endpoint
+            {
+                let mut message =
+                    format!("type alias `{alias}` points at unknown diagram type `{target}`");
+                if let Some(closest) = types::closest(&target) {
+                    message.push_str(&format!("; did you mean `{closest}`?"));
+                }
+                bail!("{message} (set allow_unknown_types = true if kroki supports it)");
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The kroki name for a diagram type as it's written in a chapter: lowercased, with aliases resolved.
+    pub fn normalize_type(&self, diagram_type: &str) -> String {
+        self.type_alias(diagram_type).map_or_else(
+            || diagram_type.to_ascii_lowercase(),
+            |alias| alias.diagram_type().to_ascii_lowercase(),
+        )
+    }
+
+    /// The alias a diagram type is written as, if it's one of the `type_aliases`.
+    pub fn type_alias(&self, diagram_type: &str) -> Option<&TypeAlias> {
+        self.type_aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(diagram_type))
+            .map(|(_, target)| target)
+    }
+
+    /// The format to render a diagram in.
+    ///
+    /// The first of these that's set wins: the diagram's `format` attribute, its type in the renderer's
+    /// `renderer_formats`, the renderer's `default` there, and then `output_format`.
+    pub fn output_format_for<'a>(
+        &'a self,
+        diagram_type: &str,
+        attribute: Option<&'a str>,
+    ) -> &'a str {
+        let renderer_formats = self.renderer_formats.get(&self.renderer);
+        attribute
+            .or_else(|| renderer_formats?.get(diagram_type).map(String::as_str))
+            .or_else(|| renderer_formats?.get("default").map(String::as_str))
+            .unwrap_or(&self.output_format)
+    }
+
+    /// The endpoint to render a diagram type with: its `KROKI_ENDPOINT_<TYPE>`, its entry in `endpoints`,
+    /// or `endpoint`.
+    pub fn endpoint_for(&self, diagram_type: &str) -> &str {
+        let diagram_type = diagram_type.to_ascii_lowercase();
+        self.env_endpoints
+            .get(&diagram_type)
+            .or_else(|| self.endpoints.get(&diagram_type))
+            .unwrap_or(&self.endpoint)
+    }
+}
+
+/// Reads an on/off environment variable, where empty, `0`, and `false` are off.
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name)
+        .ok()
+        .map(|value| !matches!(value.as_str(), "" | "0" | "false"))
+}
+
+/// Replaces `$NAME` and `${NAME}` with environment variables, so secrets can stay out of `book.toml`.
+///
+/// `$$` is a literal `$`. Fails if a variable isn't set.
+fn expand_env(value: &str) -> Result<String> {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, remaining) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .with_context(|| format!("unclosed `${{` in `{value}`"))?;
+            if end == 0 {
+                bail!("empty `${{}}` in `{value}`");
+            }
+            (&braced[..end], &braced[end + 1..])
+        } else if let Some(remaining) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = remaining;
+            continue;
+        } else {
+            let end = after.find(|c| !is_name(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            let variable = std::env::var(name)
+                .with_context(|| format!("environment variable {name} isn't set"))?;
+            expanded.push_str(&variable);
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn with_trailing_slash(mut url: String) -> String {
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    url
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            skip_rendering: false,
+            skip_drafts: false,
+            validate_only: false,
+            verbose: false,
+            endpoint: "https://kroki.io/".to_string(),
+            type_aliases: HashMap::new(),
+            fenced_types: Vec::new(),
+            renderer_endpoints: HashMap::new(),
+            endpoints: HashMap::new(),
+            env_endpoints: HashMap::new(),
+            capabilities_url: None,
+            renderer: String::new(),
+            renderers: vec!["html".to_string()],
+            default_diagram_type: None,
+            output_format: "svg".to_string(),
+            renderer_formats: HashMap::new(),
+            headers: HashMap::new(),
+            type_headers: HashMap::new(),
+            method: RequestMethod::Post,
+            request_envelope: None,
+            response_pointer: None,
+            json_response_pointer: "/data".to_string(),
+            retries: 0,
+            retry_backoff_ms: 500,
+            retry_statuses: None,
+            retry_budget: None,
+            timeout_seconds: 0,
+            print_variant: None,
+            theme_aware: false,
+            dark_options: HashMap::from([("theme".to_string(), "dark".to_string())]),
+            max_concurrency: None,
+            ramp_up: false,
+            type_concurrency: HashMap::new(),
+            default_type_concurrency: None,
+            cache: true,
+            cache_dir: PathBuf::from(".kroki-cache"),
+            mode: Mode::Http,
+            local_command: Vec::new(),
+            local_graphviz: false,
+            dot_command: PathBuf::from("dot"),
+            alt_from_type: false,
+            search_text: false,
+            output: Output::Inline,
+            reserve_space: false,
+            wrap_svg: true,
+            wrapper_tag: WrapperTag::Pre,
+            wrapper_class: None,
+            responsive: false,
+            wrap_svg_types: Vec::new(),
+            auto_object_bytes: 100_000,
+            auto_object_markers: ["<a ", "href=", "onclick=", "<script"]
+                .map(String::from)
+                .to_vec(),
+            pad_blank_lines: true,
+            assets_dir: PathBuf::from("kroki-assets"),
+            asset_layout: AssetLayout::Hash,
+            prune_assets: false,
+            link_to_full: false,
+            loading_placeholder: false,
+            asset_url_prefix: None,
+            site_base_url: None,
+            gallery_wrapper: "<div class=\"kroki-gallery\">\n\n{items}\n\n</div>".to_string(),
+            gallery_item: "<figure class=\"kroki-gallery-item\">\n\n{diagram}\n\n<figcaption>{caption}</figcaption>\n</figure>".to_string(),
+            also_formats: Vec::new(),
+            autorender_extensions: Vec::new(),
+            csp_safe: false,
+            on_error: OnError::Fail,
+            max_errors: None,
+            on_unsupported_type: OnUnsupportedType::Fail,
+            allow_unknown_types: false,
+            on_missing_svg: OnMissingSvg::Error,
+            total_output_budget: None,
+            on_over_budget: OnOverBudget::Fail,
+            max_svg_bytes: None,
+            oversize: Oversize::Warn,
+            validate_svg: false,
+            hoist_styles: false,
+            sprite_sheet: false,
+            source_encoding: SourceEncoding::Plain,
+            embed_images: false,
+            plantuml_includes: false,
+            on_missing_include: OnMissingInclude::Fail,
+            plantuml_autowrap: false,
+            include_base: None,
+            plantuml_config: None,
+            golden_dir: None,
+            update_goldens: false,
+            dump_requests: DumpRequests::Stderr(false),
+            required_attributes: Vec::new(),
+            diagnostics_format: DiagnosticsFormat::Text,
+            diagnostics_file: None,
+        }
+    }
+}
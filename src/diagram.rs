@@ -0,0 +1,1003 @@
+use crate::config::{RequestMethod, SourceEncoding};
+use crate::embed::embed_images;
+use crate::plantuml::{autowrap, inject_config, inline_includes, is_plantuml};
+use crate::render::{chapter_name, Renderer};
+use crate::template;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use xmltree::Element;
+
+/// A diagram found in a chapter's markdown.
+pub struct Diagram {
+    /// Empty if the diagram doesn't say, until it's filled in from `default_diagram_type`.
+    pub diagram_type: String,
+    pub content: DiagramContent,
+
+    /// Attributes from the `<kroki>` tag or the code block's info string.
+    pub attributes: HashMap<String, String>,
+
+    /// Byte range of the markup in the chapter that the rendered diagram replaces.
+    pub range: Range<usize>,
+}
+
+/// Where a diagram's source code comes from.
+pub enum DiagramContent {
+    /// Code inlined in the chapter.
+    Raw(String),
+
+    /// Code in an external file.
+    Path { path: PathBuf, root: PathRoot },
+
+    /// Code fetched over HTTP, from a `path` with `root="url"`.
+    Url(String),
+}
+
+/// What a diagram's file reference is relative to.
+#[derive(Clone, Copy)]
+pub enum PathRoot {
+    /// The system root. Requires an absolute path.
+    System,
+    /// The book root, where `book.toml` is.
+    Book,
+    /// The sources root, typically `<book root>/src`.
+    Source,
+    /// The directory of the current chapter, or the diagram's `base` attribute if it has one.
+    This,
+}
+
+impl PathRoot {
+    pub fn parse(root: Option<&str>) -> Result<Self> {
+        Ok(match root {
+            Some("system") => PathRoot::System,
+            Some("book") => PathRoot::Book,
+            Some("source" | "src") => PathRoot::Source,
+            None | Some("this" | ".") => PathRoot::This,
+            Some(other) => bail!("unrecognized root type: {other}"),
+        })
+    }
+}
+
+/// Absolute locations of the roots that file references can extend from.
+pub struct Roots {
+    pub book: PathBuf,
+    pub source: PathBuf,
+    /// Directory that `book` and `source` file references extend from, relative to those roots.
+    pub include_base: PathBuf,
+}
+
+/// Body of a kroki render request.
+#[derive(Serialize, Clone)]
+pub struct KrokiRequestBody {
+    pub diagram_source: String,
+    pub diagram_type: String,
+    pub output_format: String,
+}
+
+impl Diagram {
+    /// Reads the diagram's source code and builds the request to send to kroki.
+    ///
+    /// `chapter_path` is the chapter's source path, relative to the sources root. Source and template files
+    /// are read without blocking, so reading many of them overlaps with requests already in flight.
+    pub async fn resolve(
+        &self,
+        renderer: &Renderer,
+        chapter_path: Option<&Path>,
+    ) -> Result<KrokiRequestBody> {
+        let roots = &renderer.roots;
+
+        // Relative references extend from the chapter's directory, unless the diagram sets a `base`
+        // (relative to the sources root), like when it's in a partial included from somewhere else.
+        let base_dir = match self.attributes.get("base") {
+            Some(base) => Some(Path::new(base.trim_start_matches('/'))),
+            None => chapter_path.and_then(Path::parent),
+        };
+
+        let (mut diagram_source, source_dir) = match &self.content {
+            DiagramContent::Raw(source) => (source.clone(), None),
+            DiagramContent::Path { path, root } => {
+                let full_path = resolve_path(path, *root, roots, base_dir)?;
+                let source = tokio::fs::read_to_string(&full_path)
+                    .await
+                    .with_context(|| format!("could not read {}", full_path.display()))?;
+                (source, full_path.parent().map(Path::to_path_buf))
+            }
+            DiagramContent::Url(url) => (
+                fetch_source(&renderer.client, &renderer.retry, url).await?,
+                None,
+            ),
+        };
+        if let SourceEncoding::Url = renderer.config.source_encoding {
+            diagram_source = percent_decode(&diagram_source).with_context(|| {
+                format!(
+                    "could not decode {} diagram source in {}",
+                    self.diagram_type,
+                    chapter_name(chapter_path)
+                )
+            })?;
+        }
+
+        // With a `data` file, the source (or the `template` file) is a template to fill in with that data.
+        if let Some(data) = self.attributes.get("data") {
+            let root = PathRoot::parse(self.attributes.get("root").map(String::as_str))?;
+            let data_path = resolve_path(Path::new(data), root, roots, base_dir)?;
+            let template_path = match self.attributes.get("template") {
+                Some(template) => {
+                    let template_path = resolve_path(Path::new(template), root, roots, base_dir)?;
+                    diagram_source = tokio::fs::read_to_string(&template_path)
+                        .await
+                        .with_context(|| format!("could not read {}", template_path.display()))?;
+                    template_path.display().to_string()
+                }
+                None => "the inline template".to_string(),
+            };
+            diagram_source = template::render(&diagram_source, &data_path).with_context(|| {
+                format!(
+                    "could not render {template_path} with data from {} in {}",
+                    data_path.display(),
+                    chapter_name(chapter_path)
+                )
+            })?;
+        }
+
+        // Files referenced by the diagram are relative to its file, or to the base directory for inlined diagrams.
+        let resolve_reference = |reference: &Path| -> Result<PathBuf> {
+            Ok(match &source_dir {
+                _ if reference.is_absolute() => reference.to_path_buf(),
+                Some(dir) => dir.join(reference),
+                None => resolve_path(reference, PathRoot::This, roots, base_dir)?,
+            })
+        };
+
+        if renderer.config.plantuml_includes && is_plantuml(&self.diagram_type) {
+            diagram_source = inline_includes(
+                &diagram_source,
+                &resolve_reference,
+                renderer.config.on_missing_include,
+                chapter_path,
+            )?;
+        }
+        if renderer.config.plantuml_autowrap && is_plantuml(&self.diagram_type) {
+            diagram_source = autowrap(&diagram_source);
+        }
+        if renderer.config.embed_images {
+            diagram_source = embed_images(&self.diagram_type, &diagram_source, resolve_reference)?;
+        }
+        if let Some(config) = &renderer.plantuml_config {
+            if is_plantuml(&self.diagram_type) {
+                diagram_source = inject_config(&diagram_source, config);
+            }
+        }
+
+        Ok(KrokiRequestBody {
+            diagram_source,
+            diagram_type: self.diagram_type.clone(),
+            output_format: renderer
+                .config
+                .output_format_for(
+                    &self.diagram_type,
+                    self.attributes.get("format").map(String::as_str),
+                )
+                .to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Decodes a percent-encoded diagram source, failing on malformed escapes instead of passing them through.
+fn percent_decode(source: &str) -> Result<String> {
+    let bytes = source.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escape = source[i..].chars().take(3).collect::<String>();
+            let byte = Some(&escape[1..])
+                .filter(|hex| hex.len() == 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow!("invalid percent escape `{escape}` at byte {i}"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).context("decoded source isn't valid UTF-8")
+}
+
+/// Resolves a file reference to an absolute path.
+///
+/// `base_dir` is what `PathRoot::This` means, relative to the sources root.
+pub fn resolve_path(
+    path: &Path,
+    root: PathRoot,
+    roots: &Roots,
+    base_dir: Option<&Path>,
+) -> Result<PathBuf> {
+    Ok(match root {
+        PathRoot::System => {
+            if path.is_relative() {
+                bail!("cannot use relative path with root=\"system\"");
+            }
+            path.to_path_buf()
+        }
+        PathRoot::Book => roots
+            .book
+            .join(&roots.include_base)
+            .join(path.strip_prefix("/").unwrap_or(path)),
+        PathRoot::Source => roots
+            .source
+            .join(&roots.include_base)
+            .join(path.strip_prefix("/").unwrap_or(path)),
+        PathRoot::This => {
+            if path.is_absolute() {
+                bail!(
+                    r#"cannot use absolute path without setting `root` attribute to "system", "book", or "source""#
+                );
+            }
+            let base_dir = base_dir.ok_or(NoSourcePath)?;
+            roots.source.join(base_dir).join(path)
+        }
+    })
+}
+
+/// Sends a request to kroki and returns the rendered SVG.
+pub async fn get_svg(
+    client: &reqwest::Client,
+    retry: &Retry,
+    envelope: &Envelope,
+    method: RequestMethod,
+    endpoint: &str,
+    body: &KrokiRequestBody,
+    headers: &HeaderMap,
+) -> Result<String> {
+    let response = send_request(client, retry, method, endpoint, body, headers, envelope).await?;
+    // Gateways wrap the SVG in their own response, so only kroki's own content type says anything.
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            envelope.response.is_none() && (value.contains("html") || value.contains("json"))
+        })
+        .map(|value| value.split(';').next().unwrap_or(value).trim().to_string());
+    let response = envelope.unwrap(&response.bytes().await?)?;
+    let response = String::from_utf8_lossy(&response);
+    let Some(content_type) = content_type else {
+        return extract_svg(&response);
+    };
+    if content_type.contains("json") {
+        if let Some(svg) = envelope.json_svg(&response) {
+            return extract_svg(&svg);
+        }
+    }
+    Err(MissingSvg {
+        tag: "<svg",
+        content_type: Some(content_type),
+        response: response.into_owned(),
+    }
+    .into())
+}
+
+/// Cuts the `<svg>` element out of a response, dropping any XML declaration, doctype, or comments around it.
+///
+/// The result can always be inlined into HTML. SVGs written to files get their declaration back from
+/// `svg::as_file`.
+pub fn extract_svg(response: &str) -> Result<String> {
+    let missing = |tag| MissingSvg {
+        tag,
+        content_type: None,
+        response: response.to_string(),
+    };
+    let start = response.find("<svg").ok_or_else(|| missing("<svg"))?;
+    let end = response.rfind("</svg>").ok_or_else(|| missing("</svg>"))?;
+    if end < start {
+        return Err(missing("</svg>").into());
+    }
+
+    Ok(response[start..end + "</svg>".len()].to_string())
+}
+
+/// Cuts the `<math>` element out of a MathML response, dropping any prolog or doctype around it.
+pub fn extract_mathml(response: &str) -> Result<String> {
+    match (response.find("<math"), response.rfind("</math>")) {
+        (Some(start), Some(end)) if start < end => {
+            Ok(response[start..end + "</math>".len()].to_string())
+        }
+        _ => bail!("didn't find a <math> element in kroki response: {response}"),
+    }
+}
+
+/// A response that should have been an SVG didn't contain one.
+#[derive(Debug)]
+pub struct MissingSvg {
+    pub tag: &'static str,
+    /// The response's content type, if it was an HTML one, or a JSON one without an SVG where it was expected.
+    pub content_type: Option<String>,
+    pub response: String,
+}
+
+impl std::fmt::Display for MissingSvg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let summary = summarize_response(&self.response);
+        match &self.content_type {
+            Some(content_type) if content_type.contains("json") => write!(
+                f,
+                "kroki responded with {content_type} without an SVG at json_response_pointer: {summary}"
+            ),
+            Some(content_type) => write!(
+                f,
+                "kroki responded with {content_type} instead of an SVG: {summary}"
+            ),
+            None => write!(f, "didn't find '{}' in kroki response: {summary}", self.tag),
+        }
+    }
+}
+
+/// The gist of an error response on one line: the message from a JSON error body, the title or text of an
+/// HTML error page, or the start of anything else.
+fn summarize_response(response: &str) -> String {
+    const MAX_CHARS: usize = 200;
+
+    let response = response.trim();
+    let json_message = serde_json::from_str::<serde_json::Value>(response)
+        .ok()
+        .and_then(|json| {
+            ["/error/message", "/error", "/message", "/detail"]
+                .iter()
+                .find_map(|pointer| Some(json.pointer(pointer)?.as_str()?.to_string()))
+        });
+    let lowercase = response.to_ascii_lowercase();
+    let html_text = lowercase.contains("<html").then(|| {
+        let title = (|| {
+            let start = lowercase.find("<title")?;
+            let start = start + lowercase[start..].find('>')? + 1;
+            let end = start + lowercase[start..].find("</title")?;
+            Some(response[start..end].to_string())
+        })();
+        title.unwrap_or_else(|| strip_tags(response))
+    });
+    let summary = json_message
+        .or(html_text)
+        .unwrap_or_else(|| response.to_string());
+
+    let summary = summary.split_whitespace().collect::<Vec<_>>().join(" ");
+    if summary.is_empty() {
+        "the response was empty".to_string()
+    } else if summary.chars().count() > MAX_CHARS {
+        format!("{}...", summary.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        summary
+    }
+}
+
+/// The text of some HTML, with its tags dropped.
+fn strip_tags(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+impl std::error::Error for MissingSvg {}
+
+/// Sends a request to kroki and returns the raw rendered file, for formats other than inlined SVG.
+pub async fn get_file(
+    client: &reqwest::Client,
+    retry: &Retry,
+    envelope: &Envelope,
+    method: RequestMethod,
+    endpoint: &str,
+    body: &KrokiRequestBody,
+    headers: &HeaderMap,
+) -> Result<Vec<u8>> {
+    let response = send_request(client, retry, method, endpoint, body, headers, envelope)
+        .await?
+        .bytes()
+        .await?;
+    envelope.unwrap(&response)
+}
+
+/// Sends a request to kroki and returns the response, so the rendered file can be streamed from it.
+///
+/// Responses aren't unwrapped, so this can't be used with a response envelope.
+pub async fn get_stream(
+    client: &reqwest::Client,
+    retry: &Retry,
+    envelope: &Envelope,
+    method: RequestMethod,
+    endpoint: &str,
+    body: &KrokiRequestBody,
+    headers: &HeaderMap,
+) -> Result<reqwest::Response> {
+    send_request(client, retry, method, endpoint, body, headers, envelope).await
+}
+
+async fn send_request(
+    client: &reqwest::Client,
+    retry: &Retry,
+    method: RequestMethod,
+    endpoint: &str,
+    body: &KrokiRequestBody,
+    headers: &HeaderMap,
+    envelope: &Envelope,
+) -> Result<reqwest::Response> {
+    let json = envelope.wrap(body)?;
+    let url = match method {
+        RequestMethod::Get => get_url(endpoint, body),
+        RequestMethod::Post => None,
+    };
+    let description = format!("kroki request for {} diagram", body.diagram_type);
+    let (response, retries) = send_with_retries(retry, &description, || match &url {
+        Some(url) => client.get(url).headers(headers.clone()),
+        None => client
+            .post(endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .headers(headers.clone())
+            .body(json.clone()),
+    })
+    .await?;
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        let message = response.text().await.unwrap_or_default();
+        // Kroki says when it doesn't know a diagram type. Other 404s, like from a wrong endpoint path or a
+        // gateway without a route for it, are reported as they are, for `Renderer` to tell apart.
+        if status == StatusCode::NOT_FOUND && message.to_lowercase().contains("diagram type") {
+            bail!(UnsupportedType {
+                diagram_type: body.diagram_type.clone(),
+                endpoint: endpoint.to_string(),
+            });
+        }
+        let error = anyhow!(KrokiError {
+            status,
+            message: message.trim().to_string(),
+        });
+        return Err(match retries {
+            0 => error,
+            _ => error.context(format!(
+                "kroki request failed after {} attempts",
+                retries + 1
+            )),
+        });
+    }
+    Ok(response)
+}
+
+/// Sends the request `build` makes, building and sending it again while it fails in a way `retry` retries.
+///
+/// Returns the response, whatever its status, and how many times it was retried.
+async fn send_with_retries(
+    retry: &Retry,
+    description: &str,
+    build: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<(reqwest::Response, u32)> {
+    let mut retries = 0;
+    loop {
+        let result = build().send().await;
+
+        let transient = match &result {
+            Ok(response) => retry.retries_status(response.status()),
+            Err(error) => error.is_connect() || error.is_timeout(),
+        };
+        if transient && retries < retry.retries && retry.take_from_budget() {
+            let delay = retry.backoff * 2u32.saturating_pow(retries);
+            retries += 1;
+            log::warn!("{description} failed, retrying in {}ms", delay.as_millis());
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        let response = result.with_context(|| match retries {
+            0 => format!("could not send {description}"),
+            _ => format!(
+                "could not send {description} after {} attempts",
+                retries + 1
+            ),
+        })?;
+        return Ok((response, retries));
+    }
+}
+
+/// Checks that a `root="url"` path is an absolute HTTP or HTTPS URL.
+fn check_url(path: &str) -> Result<String> {
+    match reqwest::Url::parse(path) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(path.to_string()),
+        Ok(url) => bail!(
+            "can't fetch diagram source from `{path}`: root=\"url\" only supports http and https, not {}",
+            url.scheme()
+        ),
+        Err(_) => bail!(
+            "`{path}` isn't an absolute URL; root=\"url\" needs a full URL like https://example.com/diagram.puml"
+        ),
+    }
+}
+
+/// Fetches a diagram's source from a URL, retrying like kroki requests.
+async fn fetch_source(client: &reqwest::Client, retry: &Retry, url: &str) -> Result<String> {
+    let description = format!("request for diagram source at {url}");
+    let (response, retries) = send_with_retries(retry, &description, || client.get(url)).await?;
+    let status = response.status();
+    if !status.is_success() {
+        let attempts = match retries {
+            0 => String::new(),
+            _ => format!(" after {} attempts", retries + 1),
+        };
+        bail!("could not fetch diagram source from {url}: responded with {status}{attempts}");
+    }
+    response
+        .text()
+        .await
+        .with_context(|| format!("could not read diagram source from {url}"))
+}
+
+/// Longest URL that GET requests are sent with. Longer ones are sent as POST requests instead,
+/// since servers and proxies commonly reject URLs much over 4 KiB.
+const MAX_GET_URL_LENGTH: usize = 4096;
+
+/// Compresses a diagram source into a zlib stream, like kroki expects in the path of GET requests.
+fn zlib(source: &str) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder
+        .write_all(source.as_bytes())
+        .expect("writing to a Vec can't fail");
+    encoder.finish().expect("writing to a Vec can't fail")
+}
+
+/// URL for a GET request, with the diagram source compressed and base64url-encoded into the path like
+/// `<endpoint><type>/<format>/<encoded source>`. `None` if it would be too long.
+fn get_url(endpoint: &str, body: &KrokiRequestBody) -> Option<String> {
+    let encoded = base64::engine::general_purpose::URL_SAFE.encode(zlib(&body.diagram_source));
+    let url = format!(
+        "{endpoint}{}/{}/{encoded}",
+        body.diagram_type, body.output_format
+    );
+    if url.len() > MAX_GET_URL_LENGTH {
+        log::debug!(
+            "GET URL for {} diagram would be {} bytes long, so it's sent as a POST request",
+            body.diagram_type,
+            url.len()
+        );
+        return None;
+    }
+    Some(url)
+}
+
+/// How requests and responses are wrapped, for gateways in front of kroki that expect an envelope.
+#[derive(Default)]
+pub struct Envelope {
+    /// JSON that the request body is put into, in place of every `"$body"` string.
+    pub request: Option<serde_json::Value>,
+    /// JSON pointer to the rendered diagram in the response.
+    pub response: Option<String>,
+    /// JSON pointer to the SVG in responses with a JSON content type, when `response` isn't set.
+    pub json_response: String,
+}
+
+impl Envelope {
+    /// Serializes a request body, wrapped in the request envelope if there is one.
+    fn wrap(&self, body: &KrokiRequestBody) -> Result<String> {
+        let Some(envelope) = &self.request else {
+            return Ok(serde_json::to_string(body)?);
+        };
+        fn fill(value: &serde_json::Value, body: &serde_json::Value) -> serde_json::Value {
+            match value {
+                serde_json::Value::String(s) if s == "$body" => body.clone(),
+                serde_json::Value::Array(items) => {
+                    items.iter().map(|item| fill(item, body)).collect()
+                }
+                serde_json::Value::Object(fields) => fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), fill(value, body)))
+                    .collect(),
+                other => other.clone(),
+            }
+        }
+        Ok(fill(envelope, &serde_json::to_value(body)?).to_string())
+    }
+
+    /// Whether responses have to be unwrapped, which means reading them whole.
+    pub fn wraps_responses(&self) -> bool {
+        self.response.is_some()
+    }
+
+    /// The SVG in a JSON response that wasn't unwrapped, at the `json_response` pointer, if it has one.
+    fn json_svg(&self, response: &str) -> Option<String> {
+        let json = serde_json::from_str::<serde_json::Value>(response).ok()?;
+        let svg = json.pointer(&self.json_response)?.as_str()?;
+        svg.contains("<svg").then(|| svg.to_string())
+    }
+
+    /// Takes the rendered diagram out of a response, if it's wrapped in an envelope.
+    fn unwrap(&self, response: &[u8]) -> Result<Vec<u8>> {
+        let Some(pointer) = &self.response else {
+            return Ok(response.to_vec());
+        };
+        let json: serde_json::Value =
+            serde_json::from_slice(response).context("gateway response isn't JSON")?;
+        match json.pointer(pointer) {
+            Some(serde_json::Value::String(output)) => Ok(output.as_bytes().to_vec()),
+            _ => bail!("didn't find a string at {pointer} in gateway response: {json}"),
+        }
+    }
+}
+
+/// How failed kroki requests are retried.
+///
+/// Connection errors, timeouts, and responses with a retried status are retried with exponential backoff.
+pub struct Retry {
+    /// Most times to retry a single request.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    pub backoff: Duration,
+    /// Retries left for the whole build, if they're limited.
+    pub budget: Option<AtomicUsize>,
+    /// Response statuses that are retried. Any 5xx status and 429 if unset.
+    pub statuses: Option<Vec<u16>>,
+}
+
+impl Retry {
+    /// Whether a response with a status might succeed if it's retried.
+    fn retries_status(&self, status: StatusCode) -> bool {
+        match &self.statuses {
+            Some(statuses) => statuses.contains(&status.as_u16()),
+            None => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// Takes a retry from the build's budget, or returns false if it's used up.
+    fn take_from_budget(&self) -> bool {
+        match &self.budget {
+            None => true,
+            Some(budget) => budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok(),
+        }
+    }
+}
+
+/// Kroki responded that it doesn't know a diagram type, like when the server is older than the preprocessor.
+#[derive(Debug)]
+pub struct UnsupportedType {
+    pub diagram_type: String,
+    pub endpoint: String,
+}
+
+impl std::fmt::Display for UnsupportedType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "kroki at {} doesn't support {} diagrams",
+            self.endpoint, self.diagram_type
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedType {}
+
+/// Kroki responded with an error, like a syntax error in a diagram's source.
+#[derive(Debug)]
+pub struct KrokiError {
+    pub status: StatusCode,
+    /// The response body, which is usually kroki's explanation of what went wrong.
+    pub message: String,
+}
+
+impl std::fmt::Display for KrokiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "kroki responded with {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for KrokiError {}
+
+/// A diagram used a local relative file reference in a chapter with no source path, like a draft or
+/// generated chapter, so there's nothing to resolve it against.
+#[derive(Debug)]
+pub struct NoSourcePath;
+
+impl std::fmt::Display for NoSourcePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot use local relative file references in chapters with no source path"
+        )
+    }
+}
+
+impl std::error::Error for NoSourcePath {}
+
+/// Scans markdown for diagrams, in order of appearance.
+///
+/// Diagrams can be written as `<kroki>` tags, `![](kroki-<type>:<path>)` image tags,
+/// or fenced code blocks with a `kroki-<type>` language or one of the `fenced_types` languages. Anything inside
+/// a `<pre>` is ignored.
+pub fn find_diagrams(content: &str, fenced_types: &[String]) -> Result<Vec<Diagram>> {
+    enum State {
+        Out,
+        InPre(usize),
+        InImage {
+            diagram_type: String,
+            path: PathBuf,
+            alt: String,
+            start: usize,
+        },
+        InKrokiReferenceTag {
+            diagram_type: String,
+            content: DiagramContent,
+            attributes: HashMap<String, String>,
+            start: usize,
+        },
+        InKrokiInlineTag {
+            diagram_type: String,
+            attributes: HashMap<String, String>,
+            content_start: usize,
+            start: usize,
+        },
+        InCode {
+            diagram_type: String,
+            attributes: HashMap<String, String>,
+            source: String,
+        },
+    }
+
+    let mut state = State::Out;
+    let mut diagrams = Vec::new();
+
+    for (event, offset) in Parser::new_ext(content, Options::all()).into_offset_iter() {
+        match event {
+            Event::Html(ref tag) if tag.as_ref() == "<pre>" => {
+                state = match state {
+                    State::InPre(n) => State::InPre(n + 1),
+                    _ => State::InPre(1),
+                };
+            }
+            Event::Html(ref tag) if tag.as_ref() == "</pre>" => match state {
+                State::InPre(n @ 2..) => state = State::InPre(n - 1),
+                State::InPre(1) => state = State::Out,
+                _ => {}
+            },
+            _ if matches!(state, State::InPre(_)) => {}
+            Event::Html(ref tag) if tag.starts_with("<kroki") => {
+                let (xml, closed) = if !tag.contains("/>") && !tag.contains("</kroki>") {
+                    (tag.to_string() + "</kroki>", false)
+                } else {
+                    (tag.to_string(), true)
+                };
+                let attributes = Element::parse(xml.as_bytes())?.attributes;
+                let diagram_type = attributes.get("type").cloned().unwrap_or_default();
+                let Some(path) = attributes.get("path") else {
+                    if closed {
+                        bail!(
+                            "kroki tag must either have an inlined diagram or a `path` attribute."
+                        );
+                    }
+                    state = State::InKrokiInlineTag {
+                        diagram_type,
+                        attributes,
+                        content_start: offset.end,
+                        start: offset.start,
+                    };
+                    continue;
+                };
+                let content = match attributes.get("root").map(String::as_str) {
+                    Some("url") => DiagramContent::Url(check_url(path)?),
+                    root => DiagramContent::Path {
+                        path: path.into(),
+                        root: PathRoot::parse(root)?,
+                    },
+                };
+                if closed {
+                    diagrams.push(Diagram {
+                        diagram_type,
+                        content,
+                        attributes,
+                        range: offset,
+                    });
+                } else {
+                    state = State::InKrokiReferenceTag {
+                        diagram_type,
+                        content,
+                        attributes,
+                        start: offset.start,
+                    };
+                }
+            }
+            Event::Html(ref tag) if tag.contains("</kroki>") => {
+                match std::mem::replace(&mut state, State::Out) {
+                    State::InKrokiInlineTag {
+                        diagram_type,
+                        attributes,
+                        content_start,
+                        start,
+                    } => diagrams.push(Diagram {
+                        diagram_type,
+                        content: DiagramContent::Raw(
+                            content[content_start..offset.start].to_string(),
+                        ),
+                        attributes,
+                        range: start..offset.end,
+                    }),
+                    State::InKrokiReferenceTag {
+                        diagram_type,
+                        content,
+                        attributes,
+                        start,
+                    } => diagrams.push(Diagram {
+                        diagram_type,
+                        content,
+                        attributes,
+                        range: start..offset.end,
+                    }),
+                    other => state = other,
+                }
+            }
+            _ if matches!(
+                state,
+                State::InKrokiReferenceTag { .. } | State::InKrokiInlineTag { .. }
+            ) => {}
+            Event::Start(Tag::Image(LinkType::Inline, ref url, _)) => {
+                let reference = url.strip_prefix("kroki-").and_then(|r| r.split_once(':'));
+                if let Some((diagram_type, path)) = reference {
+                    state = State::InImage {
+                        diagram_type: diagram_type.to_string(),
+                        path: path.into(),
+                        alt: String::new(),
+                        start: offset.start,
+                    };
+                }
+            }
+            Event::End(Tag::Image(..)) => match std::mem::replace(&mut state, State::Out) {
+                State::InImage {
+                    diagram_type,
+                    path,
+                    alt,
+                    start,
+                } => diagrams.push(Diagram {
+                    diagram_type,
+                    content: DiagramContent::Path {
+                        path,
+                        root: PathRoot::This,
+                    },
+                    attributes: if alt.is_empty() {
+                        HashMap::new()
+                    } else {
+                        HashMap::from([("alt".to_string(), alt)])
+                    },
+                    range: start..offset.end,
+                }),
+                other => state = other,
+            },
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) => {
+                let (lang, rest) = info.split_once(char::is_whitespace).unwrap_or((info, ""));
+                let diagram_type = match lang {
+                    "kroki" => Some(""),
+                    lang if fenced_types
+                        .iter()
+                        .any(|fenced| fenced.eq_ignore_ascii_case(lang)) =>
+                    {
+                        Some(lang)
+                    }
+                    lang => lang.strip_prefix("kroki-"),
+                };
+                if let Some(diagram_type) = diagram_type {
+                    state = State::InCode {
+                        diagram_type: diagram_type.to_string(),
+                        attributes: parse_attributes(rest)?,
+                        source: String::new(),
+                    };
+                }
+            }
+            Event::Text(ref text) => match &mut state {
+                State::InCode { source, .. } => source.push_str(text),
+                State::InImage { alt, .. } => alt.push_str(text),
+                _ => {}
+            },
+            Event::End(Tag::CodeBlock(..)) => match std::mem::replace(&mut state, State::Out) {
+                State::InCode {
+                    diagram_type,
+                    attributes,
+                    source,
+                } => diagrams.push(Diagram {
+                    diagram_type,
+                    content: DiagramContent::Raw(source),
+                    attributes,
+                    range: offset,
+                }),
+                other => state = other,
+            },
+            _ => {}
+        }
+    }
+
+    Ok(diagrams)
+}
+
+/// Parses `key="value"` attributes from a code block's info string.
+///
+/// Values can be double-quoted, single-quoted, or unquoted. A key without a value is a flag and gets an empty value.
+pub fn parse_attributes(text: &str) -> Result<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+
+        let value = if let Some(after_equals) = rest.strip_prefix('=') {
+            match after_equals.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = after_equals[1..]
+                        .find(quote)
+                        .ok_or_else(|| anyhow!("unclosed quote in attribute `{key}`"))?;
+                    rest = &after_equals[end + 2..];
+                    &after_equals[1..end + 1]
+                }
+                _ => {
+                    let end = after_equals
+                        .find(char::is_whitespace)
+                        .unwrap_or(after_equals.len());
+                    rest = &after_equals[end..];
+                    &after_equals[..end]
+                }
+            }
+        } else {
+            ""
+        };
+
+        attributes.insert(key.to_string(), value.to_string());
+        rest = rest.trim_start();
+    }
+
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn zlib_round_trips() {
+        let source = "digraph G {\n  a -> b;\n  b -> c;\n  a -> b;\n  b -> c;\n}\n";
+        let mut decoded = String::new();
+        ZlibDecoder::new(zlib(source).as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, source);
+    }
+
+    #[test]
+    fn svg_closed_before_it_opens_is_missing() {
+        let error = extract_svg("</svg> stray output <svg xmlns=\"http://www.w3.org/2000/svg\">")
+            .unwrap_err();
+        let missing = error.downcast_ref::<MissingSvg>().unwrap();
+        assert_eq!(missing.tag, "</svg>");
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Name of the files that set defaults for the diagrams in a directory and the directories below it.
+pub const FILE_NAME: &str = ".krokiconfig";
+
+/// Default diagram attributes for a chapter, from the `.krokiconfig` files in its directory and every
+/// directory above it up to the sources root.
+///
+/// Files closer to the chapter override ones farther away. Chapters with no source path get no defaults.
+pub fn defaults_for(source: &Path, chapter_path: Option<&Path>) -> Result<HashMap<String, String>> {
+    let mut defaults = HashMap::new();
+    let Some(chapter_dir) = chapter_path.and_then(Path::parent) else {
+        return Ok(defaults);
+    };
+    let dirs = chapter_dir.ancestors().collect::<Vec<_>>();
+    for dir in dirs.into_iter().rev() {
+        let path = source.join(dir).join(FILE_NAME);
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let table = text
+            .parse::<toml::Value>()
+            .with_context(|| format!("could not parse {}", path.display()))?;
+        defaults.extend(
+            attributes(table).with_context(|| format!("invalid settings in {}", path.display()))?,
+        );
+    }
+    Ok(defaults)
+}
+
+/// Turns a `.krokiconfig` file into diagram attributes.
+///
+/// Top-level keys are attributes, and the `options` table holds kroki diagram options, which become
+/// `option-<name>` attributes.
+fn attributes(table: toml::Value) -> Result<HashMap<String, String>> {
+    let toml::Value::Table(table) = table else {
+        bail!("expected a table");
+    };
+    let mut attributes = HashMap::new();
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("options", toml::Value::Table(options)) => {
+                for (name, value) in options {
+                    let value = scalar(&name, value)?;
+                    attributes.insert(format!("option-{name}"), value);
+                }
+            }
+            (_, value) => {
+                let value = scalar(&key, value)?;
+                attributes.insert(key, value);
+            }
+        }
+    }
+    Ok(attributes)
+}
+
+fn scalar(key: &str, value: toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(value) => value,
+        toml::Value::Integer(value) => value.to_string(),
+        toml::Value::Float(value) => value.to_string(),
+        toml::Value::Boolean(value) => value.to_string(),
+        _ => bail!("`{key}` must be a string, number, or boolean"),
+    })
+}
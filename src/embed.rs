@@ -0,0 +1,77 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use regex::{Captures, Regex};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Replaces local image references in a diagram's source with base64 `data:` URIs,
+/// since kroki can't read files from this machine.
+///
+/// Supported references are PlantUML's `<img:path>` (including `c4plantuml`) and
+/// `<img src="path">` in Mermaid labels. Other diagram types are returned unchanged,
+/// as are references that are already URLs. `resolve` turns a referenced path into a full path.
+pub fn embed_images(
+    diagram_type: &str,
+    source: &str,
+    resolve: impl Fn(&Path) -> Result<PathBuf>,
+) -> Result<String> {
+    static PLANTUML: OnceLock<Regex> = OnceLock::new();
+    static MERMAID: OnceLock<Regex> = OnceLock::new();
+
+    let pattern = match diagram_type {
+        "plantuml" | "c4plantuml" => {
+            PLANTUML.get_or_init(|| Regex::new(r"(<img:)([^>{}]+)").unwrap())
+        }
+        "mermaid" => {
+            MERMAID.get_or_init(|| Regex::new(r#"(<img\s[^>]*?src\s*=\s*["'])([^"']+)"#).unwrap())
+        }
+        _ => return Ok(source.to_string()),
+    };
+
+    let mut error = None;
+    let embedded = pattern.replace_all(source, |captures: &Captures| {
+        let reference = captures[2].trim();
+        if is_url(reference) {
+            return captures[0].to_string();
+        }
+        match data_uri(reference, &resolve) {
+            Ok(uri) => format!("{}{uri}", &captures[1]),
+            Err(e) => {
+                error.get_or_insert(e);
+                captures[0].to_string()
+            }
+        }
+    });
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(embedded.into_owned()),
+    }
+}
+
+fn is_url(reference: &str) -> bool {
+    ["data:", "http://", "https://"]
+        .iter()
+        .any(|scheme| reference.starts_with(scheme))
+}
+
+fn data_uri(reference: &str, resolve: &impl Fn(&Path) -> Result<PathBuf>) -> Result<String> {
+    let path = resolve(Path::new(reference))?;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let mime = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        _ => bail!("can't embed {}: unrecognized image type", path.display()),
+    };
+    let bytes =
+        std::fs::read(&path).with_context(|| format!("could not read image {}", path.display()))?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+    Ok(format!("data:{mime};base64,{encoded}"))
+}
@@ -0,0 +1,245 @@
+use crate::autorender::diagram_type_for;
+use crate::diagram::{parse_attributes, resolve_path, PathRoot, Roots};
+use crate::render::escape_html;
+use anyhow::{bail, Context, Result};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const DIRECTIVE: &str = "{{#kroki-gallery";
+
+/// A directive that `expand` replaced, by where it was in the chapter and where its gallery ended up.
+pub struct Expansion {
+    pub original: Range<usize>,
+    pub expanded: Range<usize>,
+}
+
+/// Expands `{{#kroki-gallery <pattern> [attributes]}}` directives into a `<kroki>` tag for each file
+/// matching the glob pattern, wrapped in the gallery markup.
+///
+/// The pattern is resolved like a diagram's `path`, with the directive's `root` and `base` attributes.
+/// Its other attributes are passed on to every diagram. Returns the expanded content, and the expansions
+/// in order for mapping locations in it back with `original_range`.
+pub fn expand(
+    content: &str,
+    roots: &Roots,
+    chapter_path: Option<&Path>,
+    wrapper: &str,
+    item: &str,
+) -> Result<(String, Vec<Expansion>)> {
+    let mut expanded = String::with_capacity(content.len());
+    let mut expansions = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find(DIRECTIVE) {
+        expanded.push_str(&rest[..start]);
+        let original_start = content.len() - rest.len() + start;
+        let expanded_start = expanded.len();
+        let after = &rest[start + DIRECTIVE.len()..];
+        let end = after
+            .find("}}")
+            .with_context(|| format!("unclosed `{DIRECTIVE}` directive"))?;
+        let arguments = after[..end].trim();
+        let (pattern, attributes) = arguments
+            .split_once(char::is_whitespace)
+            .unwrap_or((arguments, ""));
+        if pattern.is_empty() {
+            bail!("`{DIRECTIVE}` directive has no pattern");
+        }
+        let mut attributes = parse_attributes(attributes)?;
+
+        let root = PathRoot::parse(attributes.get("root").map(String::as_str))?;
+        let base_dir = match attributes.get("base") {
+            Some(base) => Some(Path::new(base.trim_start_matches('/'))),
+            None => chapter_path.and_then(Path::parent),
+        };
+        // The directories before the first wildcard are resolved like any path, and globbed from there.
+        let (prefix, wildcards) = split_pattern(pattern);
+        let dir = resolve_path(
+            Path::new(if prefix.is_empty() { "." } else { &prefix }),
+            root,
+            roots,
+            base_dir,
+        )
+        .with_context(|| format!("could not resolve gallery pattern `{pattern}`"))?;
+        let files = glob(&dir, &wildcards)
+            .with_context(|| format!("could not search for gallery pattern `{pattern}`"))?;
+        if files.is_empty() {
+            bail!(
+                "gallery pattern `{pattern}` didn't match any files in {}",
+                dir.display()
+            );
+        }
+
+        let diagram_type = attributes.remove("type");
+        let mut attributes = attributes.into_iter().collect::<Vec<_>>();
+        attributes.sort();
+        let items = files
+            .iter()
+            .map(|file| {
+                let path = if prefix.is_empty() {
+                    file.display().to_string()
+                } else {
+                    format!("{}/{}", prefix.trim_end_matches('/'), file.display())
+                };
+                let diagram_type = diagram_type
+                    .clone()
+                    .unwrap_or_else(|| diagram_type_for(file));
+                let mut tag = format!(
+                    r#"<kroki type="{}" path="{}""#,
+                    escape_html(&diagram_type),
+                    escape_html(&path)
+                );
+                for (name, value) in &attributes {
+                    tag.push_str(&format!(r#" {name}="{}""#, escape_html(value)));
+                }
+                tag.push_str(" />");
+                let caption = file
+                    .file_name()
+                    .map(|name| escape_html(&name.to_string_lossy()))
+                    .unwrap_or_default();
+                item.replace("{diagram}", &tag)
+                    .replace("{caption}", &caption)
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        expanded.push_str(&wrapper.replace("{items}", &items));
+        rest = &after[end + "}}".len()..];
+        expansions.push(Expansion {
+            original: original_start..content.len() - rest.len(),
+            expanded: expanded_start..expanded.len(),
+        });
+    }
+    expanded.push_str(rest);
+    Ok((expanded, expansions))
+}
+
+/// Maps a range in expanded content back to the content as written. Ranges in a gallery map to its directive.
+pub fn original_range(expansions: &[Expansion], range: &Range<usize>) -> Range<usize> {
+    let original = |offset: usize| match expansions
+        .iter()
+        .rev()
+        .find(|expansion| expansion.expanded.start <= offset)
+    {
+        Some(expansion) => offset - expansion.expanded.end + expansion.original.end,
+        None => offset,
+    };
+    match expansions
+        .iter()
+        .find(|expansion| expansion.expanded.contains(&range.start))
+    {
+        Some(expansion) => expansion.original.clone(),
+        None => original(range.start)..original(range.end),
+    }
+}
+
+/// Splits a pattern into its leading directories without wildcards and the rest.
+fn split_pattern(pattern: &str) -> (String, Vec<String>) {
+    let components = pattern.split('/').collect::<Vec<_>>();
+    let literal = components
+        .iter()
+        .take_while(|component| !has_wildcard(component))
+        .count()
+        .min(components.len() - 1);
+    let prefix = components[..literal].join("/");
+    let prefix = if prefix.is_empty() && pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        prefix
+    };
+    let wildcards = components[literal..]
+        .iter()
+        .filter(|component| !component.is_empty() && **component != ".")
+        .map(|component| component.to_string())
+        .collect();
+    (prefix, wildcards)
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Finds files under `dir` matching a pattern's components, as paths relative to it.
+///
+/// `*` and `?` match within a component, and a `**` component matches any number of directories.
+fn glob(dir: &Path, components: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    walk(dir, &PathBuf::new(), components, &mut files)?;
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+fn walk(
+    dir: &Path,
+    relative: &Path,
+    components: &[String],
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let Some((first, rest)) = components.split_first() else {
+        return Ok(());
+    };
+    if !has_wildcard(first) {
+        let path = dir.join(first);
+        if rest.is_empty() && path.is_file() {
+            files.push(relative.join(first));
+        } else if path.is_dir() {
+            walk(&path, &relative.join(first), rest, files)?;
+        }
+        return Ok(());
+    }
+    if first == "**" {
+        walk(dir, relative, rest, files)?;
+    }
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("could not read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        if first == "**" {
+            if path.is_dir() {
+                walk(&path, &relative.join(&name), components, files)?;
+            }
+        } else if matches(first, &name) {
+            if rest.is_empty() && path.is_file() {
+                files.push(relative.join(&name));
+            } else if !rest.is_empty() && path.is_dir() {
+                walk(&path, &relative.join(&name), rest, files)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file name matches a pattern component, where `*` matches any run of characters and `?` any one.
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    // Backtracking match: remember the last `*` and how much of the name it has taken.
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,98 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Golden files of rendered diagrams, for noticing when a kroki upgrade changes its output.
+///
+/// Goldens are named like diagrams in the `mirror` asset layout, with an `.svg` extension.
+pub struct Goldens {
+    pub dir: PathBuf,
+    /// Write rendered diagrams over the goldens instead of comparing against them.
+    pub update: bool,
+    mismatches: Mutex<Vec<String>>,
+}
+
+impl Goldens {
+    pub fn new(dir: PathBuf, update: bool) -> Self {
+        Goldens {
+            dir,
+            update,
+            mismatches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Compares a rendered SVG against its golden, or updates the golden.
+    ///
+    /// Mismatches are only recorded here; `report` fails the build with all of them at once.
+    pub fn check(&self, stem: &str, svg: &str) -> Result<()> {
+        let path = self.dir.join(format!("{stem}.svg"));
+        if self.update {
+            if std::fs::read_to_string(&path).is_ok_and(|golden| golden == svg) {
+                return Ok(());
+            }
+            let parent = path.parent().unwrap_or(&self.dir);
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("could not create {}", parent.display()))?;
+            std::fs::write(&path, svg)
+                .with_context(|| format!("could not write {}", path.display()))?;
+            log::info!("updated golden {}", path.display());
+            return Ok(());
+        }
+
+        let mismatch = match std::fs::read_to_string(&path) {
+            Ok(golden) if golden == svg => return Ok(()),
+            Ok(golden) => format!("{}: {}", path.display(), first_difference(&golden, svg)),
+            Err(_) => format!("{}: no golden file", path.display()),
+        };
+        self.mismatches
+            .lock()
+            .expect("golden mismatches lock poisoned")
+            .push(mismatch);
+        Ok(())
+    }
+
+    /// Fails if any rendered diagram didn't match its golden. Call this once every chapter has been rendered.
+    pub fn report(&self) -> Result<()> {
+        let mut mismatches = self
+            .mismatches
+            .lock()
+            .expect("golden mismatches lock poisoned");
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+        mismatches.sort();
+        bail!(
+            "rendered diagrams don't match their goldens; set KROKI_UPDATE_GOLDENS=1 to accept the new output\n  {}",
+            mismatches.join("\n  ")
+        )
+    }
+}
+
+/// Describes where a rendered diagram first differs from its golden.
+///
+/// SVGs are often a single long line, so this shows a snippet from the first differing character.
+fn first_difference(golden: &str, rendered: &str) -> String {
+    let offset = golden
+        .char_indices()
+        .zip(rendered.chars())
+        .find(|((_, expected), actual)| expected != actual)
+        .map_or(golden.len().min(rendered.len()), |((offset, _), _)| offset);
+    let line = golden[..offset].matches('\n').count() + 1;
+    let snippet = |text: &str| {
+        let rest: String = text[offset..]
+            .chars()
+            .take_while(|&c| c != '\n')
+            .take(60)
+            .collect();
+        if rest.is_empty() {
+            "<end of line>".to_string()
+        } else {
+            rest
+        }
+    };
+    format!(
+        "differs at line {line}\n    golden:   {}\n    rendered: {}",
+        snippet(golden),
+        snippet(rendered)
+    )
+}
@@ -0,0 +1,35 @@
+#[cfg(feature = "local-graphviz")]
+use crate::diagram::extract_svg;
+#[cfg(feature = "local-graphviz")]
+use crate::local;
+#[cfg(feature = "local-graphviz")]
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "local-graphviz")]
+use std::path::PathBuf;
+
+/// Whether a diagram type is graphviz, which can be rendered locally.
+pub fn is_graphviz(diagram_type: &str) -> bool {
+    matches!(diagram_type, "graphviz" | "dot")
+}
+
+/// Renders a graphviz diagram to SVG by piping it through a local `dot` command instead of kroki.
+#[cfg(feature = "local-graphviz")]
+pub async fn render(command: PathBuf, source: String) -> Result<String> {
+    let output = local::pipe(command.clone(), vec!["-Tsvg".to_string()], source)
+        .await
+        .with_context(|| {
+            format!(
+                "could not run {}; is graphviz installed?",
+                command.display()
+            )
+        })?;
+    if !output.status.success() {
+        bail!(
+            "{} failed ({}): {}",
+            command.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    extract_svg(&String::from_utf8_lossy(&output.stdout))
+}
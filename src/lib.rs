@@ -0,0 +1,194 @@
+#![doc = include_str!("../README.md")]
+
+mod assets;
+mod autorender;
+mod cache;
+mod capabilities;
+mod concurrency;
+mod config;
+mod diagram;
+mod dir_config;
+mod embed;
+mod gallery;
+mod golden;
+mod graphviz;
+mod local;
+mod plantuml;
+mod render;
+mod svg;
+mod template;
+mod types;
+
+use anyhow::{bail, Result};
+use config::Config;
+use futures::Future;
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+use render::Renderer;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::OnceLock;
+
+/// The preprocessor, for mdbook or a custom driver to run on a book.
+pub struct KrokiPreprocessor;
+
+impl Preprocessor for KrokiPreprocessor {
+    fn name(&self) -> &'static str {
+        "kroki-preprocessor"
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book> {
+        render_book(ctx, book)
+    }
+
+    /// Only asked when the book doesn't set `renderers`, which mdbook checks itself, so this is the default.
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        Config::default()
+            .renderers
+            .iter()
+            .any(|supported| supported == renderer)
+    }
+}
+
+/// Renders every diagram in a book, with the settings in the context's `[preprocessor.kroki-preprocessor]` table.
+///
+/// This is what the preprocessor runs, for embedding it without going through mdbook's preprocessor protocol.
+pub fn render_book(ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
+    let config = Config::from_context(ctx, KrokiPreprocessor.name())?;
+    if config.skip_rendering {
+        log::info!("skipping diagram rendering, so diagrams are left as-is");
+        return Ok(book);
+    }
+    // Diagrams are rendered into the book's chapters, so a copy of them is kept to return when only validating.
+    let original = config.validate_only.then(|| book.clone());
+    let renderer = Renderer::new(ctx, config)?;
+
+    let mut occurrences = HashMap::new();
+    let render_futures = extract_render_futures(&mut book.sections, &mut occurrences, &renderer);
+
+    let (rendered_files, autorendered) = runtime().block_on(futures::future::join(
+        futures::future::join_all(render_futures),
+        renderer.autorender(),
+    ));
+    let rendered_files = rendered_files
+        .into_iter()
+        .collect::<Result<Vec<RenderedFile>>>()?;
+    renderer.log_summary();
+    autorendered?;
+    renderer.check_failures()?;
+    renderer.check_output_budget()?;
+    renderer.check_goldens()?;
+    renderer.check_unused_assets()?;
+    if let Some(original) = original {
+        return Ok(original);
+    }
+
+    let mut rendered_files = rendered_files
+        .into_iter()
+        .map(|file| (file.key, file.content))
+        .collect::<HashMap<_, _>>();
+    let mut occurrences = HashMap::new();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            let key = ChapterKey::new(chapter, &mut occurrences);
+            if let Some(content) = rendered_files.remove(&key) {
+                chapter.content = content;
+            }
+        }
+    });
+    if let Some(key) = rendered_files.keys().next() {
+        bail!(
+            "could not put rendered diagrams back into {}; was it removed while rendering?",
+            render::chapter_name(key.source.as_deref())
+        );
+    }
+
+    Ok(book)
+}
+
+/// The tokio runtime that diagrams are rendered on.
+///
+/// It's created on first use and reused by every later `run` in the same process, so embedding the
+/// preprocessor in a long-lived process (like a server that rebuilds on changes) doesn't start new
+/// threads for every build.
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().expect("tokio runtime"))
+}
+
+/// Recursively scans all chapters and turns their contents into
+/// rendered file futures.
+fn extract_render_futures<'a>(
+    items: impl IntoIterator<Item = &'a mut BookItem> + 'a,
+    occurrences: &mut HashMap<ChapterKey, usize>,
+    renderer: &'a Renderer,
+) -> Vec<Pin<Box<dyn Future<Output = Result<RenderedFile>> + 'a>>> {
+    let mut files = Vec::new();
+    for item in items {
+        if let BookItem::Chapter(ref mut chapter) = item {
+            let key = ChapterKey::new(chapter, occurrences);
+            files.extend(extract_render_futures(
+                &mut chapter.sub_items,
+                occurrences,
+                renderer,
+            ));
+            if renderer.config.skip_drafts && chapter.source_path.is_none() {
+                continue;
+            }
+            let chapter_content = chapter.content.split_off(0);
+            files.push(Box::pin(async move {
+                let new_content = renderer
+                    .render_chapter(key.source.as_deref(), &key.name, chapter_content)
+                    .await
+                    .map_err(|error| match &key.source {
+                        Some(_) => error,
+                        None => error.context(format!(
+                            "could not render chapter \"{}\", which has no source path",
+                            key.name
+                        )),
+                    })?;
+                Ok(RenderedFile {
+                    key,
+                    content: new_content,
+                })
+            }));
+        }
+    }
+    files
+}
+
+/// Identifies a chapter independently of its position in the book, so rendered content goes back
+/// into the right chapter even if the book's chapters are reordered while rendering.
+///
+/// Chapters are identified by their source path, name, and parents' names. Chapters that share all
+/// three (like two drafts with the same name) are told apart by the order they appear in.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ChapterKey {
+    source: Option<PathBuf>,
+    name: String,
+    parent_names: Vec<String>,
+    occurrence: usize,
+}
+
+impl ChapterKey {
+    /// The key for the next chapter in book order, counting how often each key has been seen in `occurrences`.
+    fn new(chapter: &Chapter, occurrences: &mut HashMap<ChapterKey, usize>) -> Self {
+        let mut key = ChapterKey {
+            source: chapter.source_path.clone(),
+            name: chapter.name.clone(),
+            parent_names: chapter.parent_names.clone(),
+            occurrence: 0,
+        };
+        let count = occurrences.entry(key.clone()).or_insert(0);
+        key.occurrence = *count;
+        *count += 1;
+        key
+    }
+}
+
+/// The result of rendering a file through kroki.
+struct RenderedFile {
+    key: ChapterKey,
+    content: String,
+}
@@ -0,0 +1,65 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::OsStr;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Output, Stdio};
+
+/// Renders a diagram with a local command instead of kroki, for `mode = "local"`.
+///
+/// `{type}` and `{format}` in the command's arguments are replaced with the diagram's type and output
+/// format. The source is written to the command's stdin, and the rendered diagram is read from its stdout.
+pub async fn render(
+    command: &[String],
+    diagram_type: &str,
+    format: &str,
+    source: String,
+) -> Result<Vec<u8>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!(r#"mode = "local" requires a local_command"#);
+    };
+    let args = args
+        .iter()
+        .map(|arg| {
+            arg.replace("{type}", diagram_type)
+                .replace("{format}", format)
+        })
+        .collect::<Vec<_>>();
+
+    let output = pipe(program.clone(), args, source)
+        .await
+        .with_context(|| format!("could not run {program}; is it installed?"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} failed to render a {diagram_type} diagram ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Runs a command with `input` on its stdin, and collects its output.
+pub async fn pipe(
+    program: impl AsRef<OsStr> + Send + 'static,
+    args: Vec<String>,
+    input: String,
+) -> Result<Output> {
+    tokio::task::spawn_blocking(move || {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Written from another thread so a large output can't fill the pipe and deadlock. Commands that
+        // exit without reading all of it are judged by their status instead.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+        let output = child.wait_with_output()?;
+        match writer.join().expect("stdin writer panicked") {
+            Err(error) if error.kind() != ErrorKind::BrokenPipe => Err(error.into()),
+            _ => Ok(output),
+        }
+    })
+    .await?
+}
@@ -0,0 +1,213 @@
+use crate::config::OnMissingInclude;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Diagram types that are rendered by PlantUML.
+pub fn is_plantuml(diagram_type: &str) -> bool {
+    matches!(diagram_type, "plantuml" | "c4plantuml")
+}
+
+/// Replaces `!include`, `!include_once`, `!include_many`, and `!includesub` directives that reference local files
+/// with the contents of those files, since kroki can't read them.
+///
+/// Includes of the standard library (`<...>`) and URLs are left for kroki. Nested includes are relative to the file
+/// that includes them. `resolve` turns an include path from the diagram itself into a full path.
+pub fn inline_includes(
+    source: &str,
+    resolve: &dyn Fn(&Path) -> Result<PathBuf>,
+    on_missing: OnMissingInclude,
+    chapter_path: Option<&Path>,
+) -> Result<String> {
+    let mut inliner = Inliner {
+        on_missing,
+        chapter_path,
+        included: HashSet::new(),
+    };
+    inliner.inline(source, resolve, 0)
+}
+
+/// Wraps a bare PlantUML source in `@startuml`/`@enduml`, unless it already has an `@start` line.
+pub fn autowrap(source: &str) -> String {
+    if source
+        .lines()
+        .any(|line| line.trim_start().starts_with("@start"))
+    {
+        return source.to_string();
+    }
+    format!("@startuml\n{}\n@enduml\n", source.trim_end())
+}
+
+/// Injects shared PlantUML settings after every `@startuml` in a diagram, or at the top if it has none.
+///
+/// Diagrams that already contain the settings are left alone.
+pub fn inject_config(source: &str, config: &str) -> String {
+    let config = config.trim();
+    if config.is_empty() || source.contains(config) {
+        return source.to_string();
+    }
+
+    let mut injected = String::with_capacity(source.len() + config.len());
+    let mut found_start = false;
+    for line in source.lines() {
+        injected.push_str(line);
+        injected.push('\n');
+        if line.trim_start().starts_with("@startuml") {
+            injected.push_str(config);
+            injected.push('\n');
+            found_start = true;
+        }
+    }
+    if !found_start {
+        injected = format!("{config}\n{source}");
+    }
+    injected
+}
+
+struct Inliner<'a> {
+    on_missing: OnMissingInclude,
+    chapter_path: Option<&'a Path>,
+    included: HashSet<PathBuf>,
+}
+
+impl Inliner<'_> {
+    fn inline(
+        &mut self,
+        source: &str,
+        resolve: &dyn Fn(&Path) -> Result<PathBuf>,
+        depth: usize,
+    ) -> Result<String> {
+        if depth > 32 {
+            bail!("PlantUML includes are nested too deeply, there may be a cycle");
+        }
+
+        let mut out = String::with_capacity(source.len());
+        for line in source.split_inclusive('\n') {
+            let Some((directive, argument)) = parse_include(line) else {
+                out.push_str(line);
+                continue;
+            };
+            if argument.starts_with('<') || argument.contains("://") {
+                out.push_str(line);
+                continue;
+            }
+
+            let (file, selector) = match argument.split_once('!') {
+                Some((file, selector)) => (file, Some(selector)),
+                None => (argument, None),
+            };
+            let path = resolve(Path::new(file))?;
+            if !path.is_file() {
+                match self.on_missing {
+                    OnMissingInclude::Fail => {
+                        bail!("PlantUML include {} not found", path.display())
+                    }
+                    OnMissingInclude::Skip => {
+                        log::warn!(
+                            "skipping missing PlantUML include {} in {}",
+                            path.display(),
+                            self.chapter_path
+                                .map_or("a chapter with no source path".into(), |p| p
+                                    .display()
+                                    .to_string())
+                        );
+                        out.push_str(&format!("' missing include: {argument}\n"));
+                        continue;
+                    }
+                }
+            }
+
+            let first_time = self
+                .included
+                .insert(path.canonicalize().unwrap_or_else(|_| path.clone()));
+            if directive == "!include_once" && !first_time {
+                continue;
+            }
+
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            let selected = if directive == "!includesub" {
+                let sub = selector.ok_or_else(|| {
+                    anyhow!("!includesub needs a sub-part name, like {file}!NAME")
+                })?;
+                select_sub(&contents, sub)
+                    .ok_or_else(|| anyhow!("no !startsub {sub} in {}", path.display()))?
+            } else {
+                select_diagram(&contents, selector).ok_or_else(|| {
+                    anyhow!("no diagram matching {argument} in {}", path.display())
+                })?
+            };
+
+            let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let nested = self.inline(&selected, &|p: &Path| Ok(dir.join(p)), depth + 1)?;
+            out.push_str(&nested);
+            if !nested.ends_with('\n') {
+                out.push('\n');
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Splits an include line into its directive and argument.
+fn parse_include(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    ["!include_once", "!include_many", "!includesub", "!include"]
+        .into_iter()
+        .find_map(|directive| {
+            let argument = line.strip_prefix(directive)?;
+            argument
+                .starts_with(char::is_whitespace)
+                .then(|| (directive, argument.trim()))
+        })
+}
+
+/// Picks one diagram out of a file, the same way PlantUML does for includes.
+///
+/// With no selector, it's the first diagram. A numeric selector picks by index, and anything else
+/// picks the diagram declared with `@startuml(id=...)`. Files without any `@start` lines are included whole.
+fn select_diagram(contents: &str, selector: Option<&str>) -> Option<String> {
+    let mut diagrams: Vec<(Option<String>, String)> = Vec::new();
+    let mut current: Option<(Option<String>, String)> = None;
+    for line in contents.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with("@start") {
+            let id = trimmed
+                .split_once("(id=")
+                .and_then(|(_, rest)| rest.split_once(')'))
+                .map(|(id, _)| id.trim().to_string());
+            current = Some((id, String::new()));
+        } else if trimmed.starts_with("@end") {
+            diagrams.extend(current.take());
+        } else if let Some((_, body)) = &mut current {
+            body.push_str(line);
+        }
+    }
+
+    if diagrams.is_empty() {
+        return Some(contents.to_string());
+    }
+    match selector {
+        None => diagrams.into_iter().next().map(|(_, body)| body),
+        Some(index) if index.parse::<usize>().is_ok() => diagrams
+            .into_iter()
+            .nth(index.parse().unwrap())
+            .map(|(_, body)| body),
+        Some(id) => diagrams
+            .into_iter()
+            .find(|(diagram_id, _)| diagram_id.as_deref() == Some(id))
+            .map(|(_, body)| body),
+    }
+}
+
+/// Extracts the lines between `!startsub <name>` and `!endsub`.
+fn select_sub(contents: &str, name: &str) -> Option<String> {
+    let mut lines = contents.split_inclusive('\n');
+    lines.find(|line| {
+        line.trim()
+            .strip_prefix("!startsub")
+            .is_some_and(|rest| rest.trim() == name)
+    })?;
+    Some(lines.take_while(|line| line.trim() != "!endsub").collect())
+}
@@ -0,0 +1,1888 @@
+use crate::assets::{
+    asset_name, copy_asset, file_digest, mirror_stem, prune_assets, relative_url, unused_assets,
+    write_asset, write_manifest,
+};
+use crate::autorender;
+use crate::cache::Cache;
+use crate::capabilities::Capabilities;
+use crate::concurrency::{Concurrency, TypeLimits};
+use crate::config::{
+    AssetLayout, Config, DiagnosticsFormat, DumpRequests, Mode, OnError, OnMissingSvg,
+    OnOverBudget, OnUnsupportedType, Output, Oversize, WrapperTag,
+};
+use crate::diagram::{
+    extract_mathml, extract_svg, find_diagrams, get_file, get_stream, get_svg, Diagram,
+    DiagramContent, Envelope, KrokiError, KrokiRequestBody, MissingSvg, NoSourcePath, PathRoot,
+    Retry, Roots, UnsupportedType,
+};
+use crate::dir_config;
+use crate::gallery;
+use crate::golden::Goldens;
+use crate::graphviz;
+use crate::local;
+use crate::svg;
+use crate::types;
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use mdbook::preprocess::PreprocessorContext;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, SemaphorePermit};
+
+/// Renders the diagrams in chapters, shared by all chapters in a book.
+pub struct Renderer {
+    pub config: Config,
+    pub roots: Roots,
+    pub client: reqwest::Client,
+    pub retry: Retry,
+    pub envelope: Envelope,
+
+    /// Contents of the `plantuml_config` file.
+    pub plantuml_config: Option<String>,
+    /// Where responses are kept: the cache directory with `cache`, and a temporary one otherwise.
+    cache: Cache,
+    /// Fetched from `capabilities_url` the first time a format is checked.
+    capabilities: OnceCell<Option<Capabilities>>,
+    concurrency: Option<Concurrency>,
+    type_limits: TypeLimits,
+    goldens: Option<Goldens>,
+    request_dump: Option<Mutex<Box<dyn Write + Send>>>,
+    diagnostics: Option<Mutex<Box<dyn Write + Send>>>,
+    inlined: Mutex<Vec<InlinedDiagram>>,
+    /// Names of the files written to the assets directory in this build.
+    written_assets: Mutex<HashSet<String>>,
+    /// Diagrams that were replaced by a placeholder because they failed, for the report at the end of the build.
+    failures: Mutex<Vec<String>>,
+    /// Whether each endpoint rendered a graphviz diagram, for telling unsupported types apart from wrong
+    /// endpoints when kroki responds 404 without saying why.
+    probes: Mutex<HashMap<String, Arc<OnceCell<bool>>>>,
+    /// Paths of the responses to this build's kroki requests in `cache`, by cache key, so identical diagrams are
+    /// only requested once.
+    responses: Mutex<HashMap<String, Arc<OnceCell<PathBuf>>>>,
+    /// When the build started, and how many diagrams were rendered and read from the cache since, for `log_summary`.
+    started: Instant,
+    rendered: AtomicUsize,
+    cache_hits: AtomicUsize,
+}
+
+/// Request slots held while a kroki request is in flight, freed when dropped.
+struct Permits<'a> {
+    _diagram_type: Option<OwnedSemaphorePermit>,
+    _request: Option<SemaphorePermit<'a>>,
+}
+
+/// Size of a diagram inlined into a chapter, for checking the book's output budget.
+struct InlinedDiagram {
+    chapter: String,
+    diagram_type: String,
+    bytes: usize,
+}
+
+impl Renderer {
+    /// Sets up a renderer for a book, opening the request dump and diagnostics file if they're configured.
+    pub fn new(ctx: &PreprocessorContext, config: Config) -> Result<Self> {
+        let request_dump: Option<Box<dyn Write + Send>> = match &config.dump_requests {
+            DumpRequests::Stderr(false) => None,
+            DumpRequests::Stderr(true) => Some(Box::new(std::io::stderr())),
+            DumpRequests::File(path) => {
+                let path = ctx.root.join(path);
+                let file = File::create(&path)
+                    .with_context(|| format!("could not create {}", path.display()))?;
+                Some(Box::new(file))
+            }
+        };
+
+        let diagnostics: Option<Box<dyn Write + Send>> =
+            match (config.diagnostics_format, &config.diagnostics_file) {
+                (DiagnosticsFormat::Text, _) => None,
+                (DiagnosticsFormat::Json, None) => Some(Box::new(std::io::stderr())),
+                (DiagnosticsFormat::Json, Some(path)) => {
+                    let path = ctx.root.join(path);
+                    let file = File::create(&path)
+                        .with_context(|| format!("could not create {}", path.display()))?;
+                    Some(Box::new(file))
+                }
+            };
+
+        let retry = Retry {
+            retries: config.retries,
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+            budget: config.retry_budget.map(AtomicUsize::new),
+            statuses: config.retry_statuses.clone(),
+        };
+
+        let envelope = Envelope {
+            request: config.request_envelope.clone(),
+            response: config.response_pointer.clone(),
+            json_response: config.json_response_pointer.clone(),
+        };
+
+        let mut client = reqwest::Client::builder();
+        if config.timeout_seconds > 0 {
+            client = client.timeout(Duration::from_secs(config.timeout_seconds));
+        }
+        let client = client.build().context("could not set up HTTP client")?;
+
+        let cache = if config.cache {
+            Cache::persistent(ctx.root.join(&config.cache_dir))
+        } else {
+            Cache::temporary()?
+        };
+
+        let concurrency = config
+            .max_concurrency
+            .filter(|&max| max > 0)
+            .map(|max| Concurrency::new(max, config.ramp_up));
+
+        let type_limits =
+            TypeLimits::new(&config.type_concurrency, config.default_type_concurrency);
+
+        let goldens = config
+            .golden_dir
+            .as_ref()
+            .map(|dir| Goldens::new(ctx.root.join(dir), config.update_goldens));
+
+        let plantuml_config = match &config.plantuml_config {
+            Some(path) => {
+                let path = ctx.root.join(path);
+                Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("could not read {}", path.display()))?,
+                )
+            }
+            None => None,
+        };
+
+        let roots = Roots {
+            book: ctx.root.clone(),
+            source: ctx.root.join(&ctx.config.book.src),
+            include_base: config.include_base.clone().unwrap_or_default(),
+        };
+
+        Ok(Renderer {
+            config,
+            roots,
+            client,
+            retry,
+            envelope,
+            plantuml_config,
+            cache,
+            capabilities: OnceCell::new(),
+            concurrency,
+            type_limits,
+            goldens,
+            request_dump: request_dump.map(Mutex::new),
+            diagnostics: diagnostics.map(Mutex::new),
+            inlined: Mutex::new(Vec::new()),
+            written_assets: Mutex::new(HashSet::new()),
+            failures: Mutex::new(Vec::new()),
+            probes: Mutex::new(HashMap::new()),
+            responses: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+            rendered: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+        })
+    }
+
+    /// Renders every diagram in a chapter's markdown and splices the results in.
+    ///
+    /// Diagrams in a chapter are rendered in parallel. `chapter_title` names chapters with no source path
+    /// in messages.
+    pub async fn render_chapter(
+        &self,
+        chapter_path: Option<&Path>,
+        chapter_title: &str,
+        mut content: String,
+    ) -> Result<String> {
+        // Messages and diagnostics locate diagrams in the chapter as written, before galleries are expanded.
+        let written = content.clone();
+        let mut expansions = Vec::new();
+        let has_gallery = content.contains("{{#kroki-gallery");
+        if has_gallery {
+            (content, expansions) = gallery::expand(
+                &content,
+                &self.roots,
+                chapter_path,
+                &self.config.gallery_wrapper,
+                &self.config.gallery_item,
+            )
+            .map_err(|error| {
+                self.diagnostic(chapter_path, None, "error", &format!("{error:#}"));
+                error.context(format!(
+                    "could not expand gallery in {}",
+                    chapter_name(chapter_path)
+                ))
+            })?;
+        }
+        let mut diagrams = find_diagrams(&content, &self.config.fenced_types).map_err(|error| {
+            self.diagnostic(chapter_path, None, "error", &format!("{error:#}"));
+            error
+        })?;
+        // Directory defaults come before `book.toml`'s: their `type` before `default_diagram_type`, and their
+        // attributes before a type alias's.
+        let mut defaults = dir_config::defaults_for(&self.roots.source, chapter_path)?;
+        let default_type = defaults
+            .remove("type")
+            .or_else(|| self.config.default_diagram_type.clone());
+        for diagram in &mut diagrams {
+            if diagram.diagram_type.is_empty() {
+                diagram.diagram_type = default_type.clone().with_context(|| {
+                    format!(
+                        "a diagram in {} has no type, and there's no default_diagram_type",
+                        chapter_name(chapter_path)
+                    )
+                })?;
+            }
+            for (name, value) in &defaults {
+                diagram
+                    .attributes
+                    .entry(name.clone())
+                    .or_insert_with(|| value.clone());
+            }
+            if let Some(alias) = self.config.type_alias(&diagram.diagram_type) {
+                for (name, value) in alias.attributes() {
+                    diagram.attributes.entry(name).or_insert(value);
+                }
+            }
+            diagram.diagram_type = self.config.normalize_type(&diagram.diagram_type);
+        }
+        let ranges = diagrams
+            .iter()
+            .map(|diagram| trim_range(&content, &diagram.range))
+            .collect::<Vec<_>>();
+        let positions = ranges
+            .iter()
+            .map(|range| gallery::original_range(&expansions, range))
+            .collect::<Vec<_>>();
+        self.check_required_attributes(chapter_path, &written, &diagrams, &positions)?;
+        self.check_on_error_attributes(chapter_path, &written, &diagrams, &positions)?;
+        self.check_diagram_types(chapter_path, &written, &diagrams, &positions)?;
+
+        if let Some(dump) = &self.request_dump {
+            for (diagram, position) in diagrams.iter().zip(&positions) {
+                let body = diagram.resolve(self, chapter_path).await.with_context(|| {
+                    format!(
+                        "could not resolve the {} diagram at line {} of {}",
+                        diagram.diagram_type,
+                        line_at(&written, position.start),
+                        chapter_name(chapter_path)
+                    )
+                })?;
+                self.dump_request(dump, chapter_path, &body)?;
+            }
+            return Ok(content);
+        }
+
+        if !diagrams.is_empty() {
+            log::log!(
+                self.progress_level(),
+                "found {} in {}",
+                diagram_count(diagrams.len()),
+                chapter_name(chapter_path)
+            );
+        }
+        let render_futures = diagrams.iter().enumerate().map(|(index, diagram)| {
+            self.timed(
+                chapter_path,
+                &diagram.diagram_type,
+                self.render_diagram(index, diagram, chapter_path, &positions[index]),
+            )
+        });
+        // Every failure gets a diagnostic, but only the first one fails the chapter. Diagrams with
+        // `on_error="show"` show their error instead. Diagrams with `on_error="placeholder"`, or any diagram
+        // with `on_error = "placeholder"` or `max_errors`, are replaced by a placeholder when they fail, unless
+        // they have `on_error="fail"`. Diagrams that only failed because they use relative file references in a
+        // chapter with no source path are left as they are.
+        let mut outputs = Vec::with_capacity(diagrams.len());
+        let mut first_error = None;
+        let results = futures::future::join_all(render_futures).await;
+        for (index, (diagram, result)) in diagrams.iter().zip(results).enumerate() {
+            let (range, position) = (&ranges[index], &positions[index]);
+            let error = match result {
+                Ok(output) => {
+                    outputs.push(output);
+                    continue;
+                }
+                Err(error) => error,
+            };
+            let timed_out = error.chain().any(|cause| {
+                cause
+                    .downcast_ref::<reqwest::Error>()
+                    .is_some_and(reqwest::Error::is_timeout)
+            });
+            let error = if timed_out {
+                error.context(format!("timed out after {}s", self.config.timeout_seconds))
+            } else {
+                error
+            };
+            let location = format!(
+                "the {} diagram at line {} of {}",
+                diagram.diagram_type,
+                line_at(&written, position.start),
+                chapter_name(chapter_path)
+            );
+            let on_error = diagram.attributes.get("on_error").map(String::as_str);
+            let placeholder = match on_error {
+                Some("show") => {
+                    log::info!("{location} failed and shows its error instead: {error:#}");
+                    let message = match error.downcast_ref::<KrokiError>() {
+                        Some(error) => error.message.clone(),
+                        None => format!("{error:#}"),
+                    };
+                    outputs.push(format!(
+                        r#"<pre class="kroki-error">{}</pre>"#,
+                        escape_html(&message)
+                    ));
+                    continue;
+                }
+                Some("fail") => false,
+                Some("placeholder") => true,
+                _ => {
+                    self.config.on_error == OnError::Placeholder || self.config.max_errors.is_some()
+                }
+            };
+            if placeholder && error.chain().any(|cause| cause.is::<NoSourcePath>()) {
+                log::warn!(
+                    "the {} diagram at line {} of chapter \"{chapter_title}\" was left unrendered: {error:#}",
+                    diagram.diagram_type,
+                    line_at(&written, position.start)
+                );
+                self.diagnostic(
+                    chapter_path,
+                    Some(position.clone()),
+                    "warning",
+                    &format!("{error:#}"),
+                );
+                outputs.push(content[range.clone()].to_string());
+                continue;
+            }
+            if placeholder || (self.config.validate_only && on_error != Some("fail")) {
+                log::warn!("{location} failed, so a placeholder was rendered: {error:#}");
+                self.failures
+                    .lock()
+                    .expect("failures lock poisoned")
+                    .push(format!("{location}: {error:#}"));
+                self.diagnostic(
+                    chapter_path,
+                    Some(position.clone()),
+                    "warning",
+                    &format!("{error:#}"),
+                );
+                outputs.push(format!(
+                    "<div class=\"kroki-failed\">\n<p>Could not render <code>{}</code> diagram:</p>\n<pre>{}</pre>\n</div>",
+                    escape_html(&diagram.diagram_type),
+                    escape_html(&format!("{error:#}"))
+                ));
+                continue;
+            }
+            self.diagnostic(
+                chapter_path,
+                Some(position.clone()),
+                "error",
+                &format!("{error:#}"),
+            );
+            first_error
+                .get_or_insert_with(|| error.context(format!("could not render {location}")));
+        }
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+
+        let style = if self.config.hoist_styles {
+            svg::hoist_styles(&mut outputs)
+        } else {
+            None
+        };
+        let sprites = if self.config.sprite_sheet {
+            svg::sprite_sheet(&mut outputs)
+        } else {
+            None
+        };
+
+        for (range, output) in ranges.iter().zip(outputs).rev() {
+            let (range, output) = if self.config.pad_blank_lines {
+                pad_blank_lines(&content, range, output)
+            } else {
+                (range.clone(), output)
+            };
+            content.replace_range(range, &output);
+        }
+
+        if let Some(sprites) = sprites {
+            content.insert_str(0, &format!("{sprites}\n\n"));
+        }
+        if let Some(style) = style {
+            content.insert_str(0, &format!("{style}\n\n"));
+        }
+        if self.config.print_variant.is_some() && !self.config.csp_safe && !diagrams.is_empty() {
+            content.insert_str(0, &format!("{VARIANT_STYLE}\n\n"));
+        }
+        if self.config.theme_aware && !self.config.csp_safe && !diagrams.is_empty() {
+            content.insert_str(0, &format!("{THEME_STYLE}\n\n"));
+        }
+        if self.config.search_text && !self.config.csp_safe && !diagrams.is_empty() {
+            content.insert_str(0, &format!("{SEARCH_TEXT_STYLE}\n\n"));
+        }
+        if self.config.loading_placeholder && !self.config.csp_safe && !diagrams.is_empty() {
+            content.insert_str(0, &format!("{LOADING_STYLE}\n\n"));
+        }
+        if has_gallery && !self.config.csp_safe {
+            content.insert_str(0, &format!("{GALLERY_STYLE}\n\n"));
+        }
+
+        Ok(content)
+    }
+
+    /// Renders one of a chapter's diagrams, and returns the markup that replaces it.
+    ///
+    /// `index` is the diagram's place in the chapter, and `position` its range in the chapter as written.
+    async fn render_diagram(
+        &self,
+        index: usize,
+        diagram: &Diagram,
+        chapter_path: Option<&Path>,
+        position: &Range<usize>,
+    ) -> Result<String> {
+        let body = diagram.resolve(self, chapter_path).await?;
+        let endpoint = self.config.endpoint_for(&body.diagram_type);
+        let headers = self.headers_for(diagram, &body.diagram_type)?;
+        let stem = match (
+            diagram.attributes.get("out"),
+            self.config.asset_layout,
+            chapter_path,
+        ) {
+            (Some(out), _, _) => Some(self.out_stem(out, &body.output_format)?),
+            (None, AssetLayout::Mirror, Some(chapter_path)) => {
+                Some(mirror_stem(chapter_path, index))
+            }
+            _ => None,
+        };
+        let caption = diagram
+            .attributes
+            .get("caption")
+            .map(|caption| escape_html(caption));
+        // Text that describes the diagram, for its title, its `aria-label`, and the `alt` of its `<img>`.
+        let label = ["tooltip", "alt", "caption"]
+            .iter()
+            .find_map(|name| diagram.attributes.get(*name).cloned())
+            .or_else(|| {
+                self.config
+                    .alt_from_type
+                    .then(|| format!("{} diagram", body.diagram_type))
+            })
+            .map(|text| escape_html(&text));
+        let mut output = if body.output_format == "svg" {
+            let mut svg = match self.render_svg(endpoint, &body, &headers).await {
+                Ok(svg) => svg,
+                Err(error) if error.is::<UnsupportedType>() => {
+                    let chapter = chapter_name(chapter_path);
+                    return match self.config.on_unsupported_type {
+                        OnUnsupportedType::Fail => Err(error),
+                        OnUnsupportedType::Placeholder => {
+                            log::warn!("{error}, so a placeholder was rendered in {chapter}");
+                            self.diagnostic(
+                                chapter_path,
+                                Some(position.clone()),
+                                "warning",
+                                &error.to_string(),
+                            );
+                            Ok(format!(
+                                r#"<div class="kroki-unsupported">Unsupported diagram type: <code>{}</code></div>"#,
+                                escape_html(&body.diagram_type)
+                            ))
+                        }
+                    };
+                }
+                Err(error) => match error.downcast_ref::<MissingSvg>() {
+                    Some(missing) if self.config.on_missing_svg == OnMissingSvg::Passthrough => {
+                        log::warn!(
+                            "kroki didn't return an SVG for a {} diagram in {}, so its response was embedded instead",
+                            body.diagram_type,
+                            chapter_name(chapter_path)
+                        );
+                        self.diagnostic(
+                            chapter_path,
+                            Some(position.clone()),
+                            "warning",
+                            &missing.to_string(),
+                        );
+                        return Ok(format!(
+                            r#"<pre class="kroki-passthrough">{}</pre>"#,
+                            escape_html(&missing.response)
+                        ));
+                    }
+                    _ => return Err(error),
+                },
+            };
+            if self.config.validate_svg {
+                svg::validate(&svg).with_context(|| {
+                    format!("kroki returned a malformed {} diagram", body.diagram_type)
+                })?;
+            }
+            if let (Some(goldens), Some(chapter_path)) = (&self.goldens, chapter_path) {
+                goldens.check(&mirror_stem(chapter_path, index), &svg)?;
+            }
+            if let Some(label) = &label {
+                svg::add_title(&mut svg, label);
+                svg::add_label(&mut svg, label);
+            }
+            // Inlined SVGs share the page's id space, so each diagram's ids get a prefix of their own.
+            let id_prefix = (self.config.output != Output::Files
+                && !diagram.attributes.contains_key("no-id-rewrite"))
+            .then(|| {
+                format!(
+                    "kroki-{}-{index}-",
+                    &svg::hex_digest(chapter_name(chapter_path).as_bytes())[..8]
+                )
+            });
+            if let Some(prefix) = &id_prefix {
+                svg = svg::namespace_ids(&svg, prefix);
+            }
+            let reserved_space = (self.config.reserve_space && !self.config.csp_safe)
+                .then(|| svg::reserved_space(&svg))
+                .flatten();
+            let mut output = self.place(
+                svg,
+                &body.diagram_type,
+                stem.as_deref(),
+                label.as_deref(),
+                caption.as_deref(),
+                chapter_path,
+            )?;
+
+            // Renders the diagram again with other diagram options, for a variant that's shown instead of it
+            // in some contexts. Its ids and file are named with `suffix`.
+            let render_variant = |suffix: &'static str, options: &HashMap<String, String>| {
+                let headers = with_diagram_options(&headers, options);
+                let (body, label, caption, id_prefix, stem) =
+                    (&body, &label, &caption, &id_prefix, &stem);
+                async move {
+                    let mut svg = self.render_svg(endpoint, body, &headers?).await?;
+                    if let Some(label) = label {
+                        svg::add_title(&mut svg, label);
+                        svg::add_label(&mut svg, label);
+                    }
+                    if let Some(prefix) = id_prefix {
+                        svg = svg::namespace_ids(&svg, &format!("{prefix}{suffix}-"));
+                    }
+                    let stem = stem.as_ref().map(|stem| format!("{stem}-{suffix}"));
+                    self.place(
+                        svg,
+                        &body.diagram_type,
+                        stem.as_deref(),
+                        label.as_deref(),
+                        caption.as_deref(),
+                        chapter_path,
+                    )
+                }
+            };
+
+            if self.config.theme_aware {
+                let dark_output = render_variant("dark", &self.config.dark_options).await?;
+                output = format!(
+                    "<div class=\"kroki-light\">\n\n{output}\n\n</div>\n<div class=\"kroki-dark\">\n\n{dark_output}\n\n</div>"
+                );
+            }
+            if let Some(style) = reserved_space {
+                output =
+                    format!("<div class=\"kroki-reserved\" style=\"{style}\">\n{output}\n</div>");
+            }
+
+            if let Some(print_options) = &self.config.print_variant {
+                let print_output = render_variant("print", print_options).await?;
+                output = format!(
+                    "<div class=\"kroki-screen\">\n\n{output}\n\n</div>\n<div class=\"kroki-print\">\n\n{print_output}\n\n</div>"
+                );
+            }
+            output
+        } else if self.config.output == Output::Files
+            && !matches!(body.output_format.as_str(), "txt" | "utxt" | "mathml")
+        {
+            let mime = file_mime(&body.output_format)?;
+            let name = self
+                .render_file_to_assets(endpoint, &body, &headers, stem.as_deref())
+                .await?;
+            let url = self.asset_url(chapter_path, &name)?;
+            self.file_element(
+                &url,
+                (mime == "application/pdf").then_some(mime),
+                label.as_deref(),
+            )
+        } else {
+            let file = self.render_file(endpoint, &body, &headers).await?;
+            self.place_file(&file, &body.output_format, label.as_deref())?
+        };
+
+        if self.config.output != Output::Files {
+            self.inlined
+                .lock()
+                .expect("inlined diagrams lock poisoned")
+                .push(InlinedDiagram {
+                    chapter: chapter_name(chapter_path),
+                    diagram_type: body.diagram_type.clone(),
+                    bytes: output.len(),
+                });
+        }
+
+        let extra_formats = match diagram.attributes.get("also") {
+            Some(also) => also
+                .split(',')
+                .map(str::trim)
+                .filter(|format| !format.is_empty())
+                .map(String::from)
+                .collect(),
+            None => self.config.also_formats.clone(),
+        };
+        if !extra_formats.is_empty() {
+            let links = self
+                .download_links(
+                    &body,
+                    &headers,
+                    &extra_formats,
+                    stem.as_deref(),
+                    chapter_path,
+                )
+                .await?;
+            output.push('\n');
+            output.push_str(&links);
+        }
+
+        if self.config.search_text {
+            let mut words: Vec<&str> = Vec::new();
+            for name in ["tooltip", "alt", "caption", "keywords"] {
+                match diagram.attributes.get(name) {
+                    Some(text) if !words.contains(&text.as_str()) => words.push(text),
+                    _ => {}
+                }
+            }
+            if !words.is_empty() {
+                output.push_str(&format!(
+                    "\n<div class=\"kroki-search-text\">{}</div>",
+                    escape_html(&words.join(" "))
+                ));
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Runs a diagram's render future, logging how long it took.
+    async fn timed<T>(
+        &self,
+        chapter_path: Option<&Path>,
+        diagram_type: &str,
+        render: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let result = render.await;
+        let elapsed = started.elapsed().as_millis();
+        let chapter = chapter_name(chapter_path);
+        match &result {
+            Ok(_) => {
+                self.rendered.fetch_add(1, Ordering::Relaxed);
+                log::log!(
+                    self.progress_level(),
+                    "rendered {diagram_type} diagram in {chapter} in {elapsed}ms"
+                );
+            }
+            Err(_) => log::log!(
+                self.progress_level(),
+                "{diagram_type} diagram in {chapter} failed after {elapsed}ms"
+            ),
+        }
+        result
+    }
+
+    /// Logs how many diagrams the build rendered, how long it took, and how many came from the cache.
+    pub fn log_summary(&self) {
+        if self.config.validate_only {
+            log::info!(
+                "validated {} in {:.1}s, so the book was left unchanged",
+                diagram_count(self.rendered.load(Ordering::Relaxed)),
+                self.started.elapsed().as_secs_f64()
+            );
+            return;
+        }
+        log::log!(
+            self.progress_level(),
+            "rendered {} in {:.1}s, {} from the cache",
+            diagram_count(self.rendered.load(Ordering::Relaxed)),
+            self.started.elapsed().as_secs_f64(),
+            self.cache_hits.load(Ordering::Relaxed)
+        );
+    }
+
+    /// The level progress is logged at: info with `verbose`, and debug otherwise.
+    fn progress_level(&self) -> log::Level {
+        if self.config.verbose {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        }
+    }
+
+    /// Renders loose diagram files under the sources root with an `autorender_extensions` extension.
+    ///
+    /// Each is written to the assets directory at its path within the sources root, plus `.svg`.
+    pub async fn autorender(&self) -> Result<()> {
+        if self.config.autorender_extensions.is_empty() {
+            return Ok(());
+        }
+        let files = autorender::find_files(
+            &self.roots.source,
+            &self.config.autorender_extensions,
+            &self.assets_dir(),
+        )?;
+        let render_futures = files.iter().map(|path| async move {
+            let diagram = Diagram {
+                diagram_type: self
+                    .config
+                    .normalize_type(&autorender::diagram_type_for(path)),
+                content: DiagramContent::Path {
+                    path: path.clone(),
+                    root: PathRoot::Source,
+                },
+                attributes: HashMap::new(),
+                range: 0..0,
+            };
+            let body = diagram.resolve(self, Some(path)).await?;
+            let endpoint = self.config.endpoint_for(&body.diagram_type);
+            let headers = self.headers_for(&diagram, &body.diagram_type)?;
+            let stem = path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if body.output_format == "svg" {
+                let svg = self.render_svg(endpoint, &body, &headers).await?;
+                if self.config.validate_svg {
+                    svg::validate(&svg)?;
+                }
+                self.write_asset(Some(&stem), svg::as_file(&svg).as_bytes(), "svg")?;
+            } else {
+                let file = self.render_file(endpoint, &body, &headers).await?;
+                self.write_asset(Some(&stem), &file, &body.output_format)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        for (path, result) in files
+            .iter()
+            .zip(futures::future::join_all(render_futures).await)
+        {
+            result.with_context(|| format!("could not render {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Fails if any diagram in a chapter is missing one of the `required_attributes`.
+    ///
+    /// Every diagram missing some gets a diagnostic, and the error lists them all.
+    fn check_required_attributes(
+        &self,
+        chapter_path: Option<&Path>,
+        content: &str,
+        diagrams: &[Diagram],
+        ranges: &[Range<usize>],
+    ) -> Result<()> {
+        let mut problems = Vec::new();
+        for (diagram, range) in diagrams.iter().zip(ranges) {
+            let missing = self
+                .config
+                .required_attributes
+                .iter()
+                .filter(|name| !diagram.attributes.contains_key(*name))
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                continue;
+            }
+            let message = format!(
+                "{} diagram at line {} is missing required attributes: {}",
+                diagram.diagram_type,
+                line_at(content, range.start),
+                missing.join(", ")
+            );
+            self.diagnostic(chapter_path, Some(range.clone()), "error", &message);
+            problems.push(message);
+        }
+        if !problems.is_empty() {
+            bail!(
+                "diagrams in {} are missing required attributes:\n  {}",
+                chapter_name(chapter_path),
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails with every diagram in a chapter whose `on_error` attribute isn't `show`, `fail`, or `placeholder`.
+    fn check_on_error_attributes(
+        &self,
+        chapter_path: Option<&Path>,
+        content: &str,
+        diagrams: &[Diagram],
+        ranges: &[Range<usize>],
+    ) -> Result<()> {
+        let mut problems = Vec::new();
+        for (diagram, range) in diagrams.iter().zip(ranges) {
+            let Some(on_error) = diagram.attributes.get("on_error") else {
+                continue;
+            };
+            if matches!(on_error.as_str(), "show" | "fail" | "placeholder") {
+                continue;
+            }
+            let message = format!(
+                "{} diagram at line {} has on_error=\"{on_error}\"; use show, fail, or placeholder",
+                diagram.diagram_type,
+                line_at(content, range.start)
+            );
+            self.diagnostic(chapter_path, Some(range.clone()), "error", &message);
+            problems.push(message);
+        }
+        if !problems.is_empty() {
+            bail!(
+                "diagrams in {} have unknown on_error values:\n  {}",
+                chapter_name(chapter_path),
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails with every diagram in a chapter whose type kroki doesn't know, unless `allow_unknown_types` is on.
+    ///
+    /// Types with their own endpoint in `endpoints` or a `KROKI_ENDPOINT_<TYPE>` variable are allowed, since
+    /// that server may support more.
+    fn check_diagram_types(
+        &self,
+        chapter_path: Option<&Path>,
+        content: &str,
+        diagrams: &[Diagram],
+        ranges: &[Range<usize>],
+    ) -> Result<()> {
+        if self.config.allow_unknown_types {
+            return Ok(());
+        }
+        let mut problems = Vec::new();
+        for (diagram, range) in diagrams.iter().zip(ranges) {
+            let diagram_type = &diagram.diagram_type;
+            if types::is_known(diagram_type)
+                || self.config.endpoint_for(diagram_type) != self.config.endpoint
+            {
+                continue;
+            }
+            let mut message = format!(
+                "unknown diagram type `{diagram_type}` at line {}",
+                line_at(content, range.start)
+            );
+            if let Some(closest) = types::closest(diagram_type) {
+                message.push_str(&format!("; did you mean `{closest}`?"));
+            }
+            self.diagnostic(chapter_path, Some(range.clone()), "error", &message);
+            problems.push(message);
+        }
+        if !problems.is_empty() {
+            bail!(
+                "diagrams in {} have unknown types (set allow_unknown_types = true if kroki supports them):\n  {}",
+                chapter_name(chapter_path),
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks the total size of every inlined diagram against `total_output_budget`.
+    ///
+    /// Call this once every chapter has been rendered. The report lists the largest diagrams.
+    pub fn check_output_budget(&self) -> Result<()> {
+        let Some(budget) = self.config.total_output_budget else {
+            return Ok(());
+        };
+        let mut inlined = self.inlined.lock().expect("inlined diagrams lock poisoned");
+        let total: usize = inlined.iter().map(|diagram| diagram.bytes).sum();
+        if total <= budget {
+            return Ok(());
+        }
+
+        inlined.sort_by_key(|diagram| std::cmp::Reverse(diagram.bytes));
+        let mut report = format!(
+            "inlined diagrams add {total} bytes to the book, over the budget of {budget} bytes. Largest diagrams:"
+        );
+        for diagram in inlined.iter().take(5) {
+            report.push_str(&format!(
+                "\n  {} bytes: {} diagram in {}",
+                diagram.bytes, diagram.diagram_type, diagram.chapter
+            ));
+        }
+
+        match self.config.on_over_budget {
+            OnOverBudget::Fail => bail!(report),
+            OnOverBudget::Warn => {
+                log::warn!("{report}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Lists files that earlier builds wrote to the assets directory and no diagram in this build did, or deletes
+    /// them with `prune_assets`.
+    ///
+    /// Call this once every chapter has been rendered.
+    pub fn check_unused_assets(&self) -> Result<()> {
+        let written = self
+            .written_assets
+            .lock()
+            .expect("written assets lock poisoned");
+        let dir = self.assets_dir();
+        if written.is_empty() || !dir.is_dir() {
+            return Ok(());
+        }
+        let unused = unused_assets(&dir, &written);
+        let mut tracked = written.clone();
+        if !unused.is_empty() && self.config.prune_assets {
+            prune_assets(&dir, &unused)?;
+            log::info!(
+                "deleted {} unused files from {}",
+                unused.len(),
+                dir.display()
+            );
+        } else if !unused.is_empty() {
+            log::info!(
+                "{} has files that no diagram uses; set prune_assets = true to delete them:\n  {}",
+                dir.display(),
+                unused.join("\n  ")
+            );
+            // They're still the preprocessor's to delete once `prune_assets` is set.
+            tracked.extend(unused);
+        }
+        write_manifest(&dir, &tracked)
+    }
+
+    /// Fails if more diagrams failed than `max_errors` allows, or warns about them if not. With `validate_only`,
+    /// any failure fails.
+    ///
+    /// Call this once every chapter has been rendered. The report lists every failure either way.
+    pub fn check_failures(&self) -> Result<()> {
+        let mut failures = self.failures.lock().expect("failures lock poisoned");
+        if failures.is_empty() {
+            return Ok(());
+        }
+        failures.sort();
+        let report = format!(
+            "{} failed to render:\n  {}",
+            diagram_count(failures.len()),
+            failures.join("\n  ")
+        );
+        match self.config.max_errors {
+            _ if self.config.validate_only => bail!("{report}"),
+            Some(max_errors) if failures.len() > max_errors => {
+                bail!("{report}\nThat's more than max_errors = {max_errors}.")
+            }
+            _ => {
+                log::warn!("{report}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks rendered diagrams against their goldens, if `golden_dir` is set.
+    ///
+    /// Call this once every chapter has been rendered. The report lists every mismatch.
+    pub fn check_goldens(&self) -> Result<()> {
+        match &self.goldens {
+            Some(goldens) => goldens.report(),
+            None => Ok(()),
+        }
+    }
+
+    /// Renders a diagram to SVG with kroki, or locally for graphviz diagrams when `local_graphviz` is on.
+    async fn render_svg(
+        &self,
+        endpoint: &str,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+    ) -> Result<String> {
+        #[cfg(feature = "local-graphviz")]
+        if self.config.local_graphviz && graphviz::is_graphviz(&body.diagram_type) {
+            return graphviz::render(self.config.dot_command.clone(), body.diagram_source.clone())
+                .await;
+        }
+        if self.config.mode == Mode::Local {
+            let svg = self.render_locally(body).await?;
+            return extract_svg(&String::from_utf8_lossy(&svg));
+        }
+
+        self.check_format(endpoint, &body.diagram_type, &body.output_format)
+            .await?;
+        let key = Cache::key(endpoint, body, headers);
+        let path = self
+            .request_once(&key, async {
+                let permits = self.acquire(&body.diagram_type).await;
+                let svg = get_svg(
+                    &self.client,
+                    &self.retry,
+                    &self.envelope,
+                    self.config.method,
+                    endpoint,
+                    body,
+                    headers,
+                )
+                .await;
+                let svg = self.explain_not_found(endpoint, body, headers, svg).await?;
+                let path = self.cache.put(&key, svg.as_bytes())?;
+                self.succeeded(permits);
+                Ok(path)
+            })
+            .await?;
+        tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("could not read {}", path.display()))
+    }
+
+    /// Renders a diagram to a file in a format other than inlined SVG.
+    async fn render_file(
+        &self,
+        endpoint: &str,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+    ) -> Result<Vec<u8>> {
+        if self.config.mode == Mode::Local {
+            return self.render_locally(body).await;
+        }
+        let path = self.store_file(endpoint, body, headers).await?;
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("could not read {}", path.display()))
+    }
+
+    /// Renders a diagram in a format other than inlined SVG into the cache, and returns its path there.
+    ///
+    /// The response is streamed to disk, unless it has to be read whole to take it out of a response envelope.
+    async fn store_file(
+        &self,
+        endpoint: &str,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+    ) -> Result<PathBuf> {
+        self.check_format(endpoint, &body.diagram_type, &body.output_format)
+            .await?;
+        let key = Cache::key(endpoint, body, headers);
+        self.request_once(&key, async {
+            let permits = self.acquire(&body.diagram_type).await;
+            let path = if self.envelope.wraps_responses() {
+                let file = get_file(
+                    &self.client,
+                    &self.retry,
+                    &self.envelope,
+                    self.config.method,
+                    endpoint,
+                    body,
+                    headers,
+                )
+                .await;
+                let file = self
+                    .explain_not_found(endpoint, body, headers, file)
+                    .await?;
+                self.cache.put(&key, &file)?
+            } else {
+                let response = get_stream(
+                    &self.client,
+                    &self.retry,
+                    &self.envelope,
+                    self.config.method,
+                    endpoint,
+                    body,
+                    headers,
+                )
+                .await;
+                let response = self
+                    .explain_not_found(endpoint, body, headers, response)
+                    .await?;
+                self.cache.put_stream(&key, response).await?
+            };
+            self.succeeded(permits);
+            Ok(path)
+        })
+        .await
+    }
+
+    /// Renders a diagram with `local_command`, limited by `max_concurrency` like kroki requests.
+    async fn render_locally(&self, body: &KrokiRequestBody) -> Result<Vec<u8>> {
+        let permits = self.acquire(&body.diagram_type).await;
+        let output = local::render(
+            &self.config.local_command,
+            &body.diagram_type,
+            &body.output_format,
+            body.diagram_source.clone(),
+        )
+        .await?;
+        self.succeeded(permits);
+        Ok(output)
+    }
+
+    /// Works out what a 404 from kroki that doesn't say why it failed means.
+    ///
+    /// Every kroki renders graphviz, so if the endpoint renders a graphviz diagram, the 404 was for a diagram
+    /// type it doesn't support. If it doesn't, the endpoint path or a gateway route is probably wrong, and the
+    /// 404 is reported as that.
+    async fn explain_not_found<T>(
+        &self,
+        endpoint: &str,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+        result: Result<T>,
+    ) -> Result<T> {
+        let error = match result {
+            Err(error)
+                if error
+                    .downcast_ref::<KrokiError>()
+                    .is_some_and(|error| error.status == reqwest::StatusCode::NOT_FOUND) =>
+            {
+                error
+            }
+            result => return result,
+        };
+        if !graphviz::is_graphviz(&body.diagram_type)
+            && self.renders_graphviz(endpoint, headers).await
+        {
+            return Err(UnsupportedType {
+                diagram_type: body.diagram_type.clone(),
+                endpoint: endpoint.to_string(),
+            }
+            .into());
+        }
+        Err(error.context(format!(
+            "every kroki renders graphviz diagrams, but {endpoint} responded 404 Not Found to one, so its \
+             path or a gateway route is probably wrong"
+        )))
+    }
+
+    /// Whether an endpoint renders a trivial graphviz diagram. Each endpoint is only probed once per build.
+    async fn renders_graphviz(&self, endpoint: &str, headers: &HeaderMap) -> bool {
+        let probe = self
+            .probes
+            .lock()
+            .expect("probes lock poisoned")
+            .entry(endpoint.to_string())
+            .or_default()
+            .clone();
+        *probe
+            .get_or_init(|| async {
+                let body = KrokiRequestBody {
+                    diagram_source: "digraph {}".to_string(),
+                    diagram_type: "graphviz".to_string(),
+                    output_format: "svg".to_string(),
+                };
+                get_svg(
+                    &self.client,
+                    &self.retry,
+                    &self.envelope,
+                    self.config.method,
+                    endpoint,
+                    &body,
+                    headers,
+                )
+                .await
+                .is_ok()
+            })
+            .await
+    }
+
+    /// Sends a kroki request that stores its response in the cache, unless an identical one was already sent in
+    /// this build or its response is in the cache. Returns the response's path in the cache.
+    ///
+    /// Identical diagrams rendered at the same time wait for the first one's response instead of sending
+    /// their own. Failed requests aren't remembered, so each diagram waiting on one tries again.
+    async fn request_once(
+        &self,
+        key: &str,
+        request: impl Future<Output = Result<PathBuf>>,
+    ) -> Result<PathBuf> {
+        let response = self
+            .responses
+            .lock()
+            .expect("responses lock poisoned")
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let path = response
+            .get_or_try_init(|| async {
+                if let Some(cached) = self.cache.get(key) {
+                    self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(cached);
+                }
+                request.await
+            })
+            .await?;
+        Ok(path.clone())
+    }
+
+    /// Renders a diagram to a file in the assets directory and returns its name.
+    ///
+    /// The response is streamed to the cache and copied from there, so it's never held in memory whole.
+    async fn render_file_to_assets(
+        &self,
+        endpoint: &str,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+        stem: Option<&str>,
+    ) -> Result<String> {
+        let format = &body.output_format;
+        if self.config.mode == Mode::Local {
+            let file = self.render_locally(body).await?;
+            return self.write_asset(stem, &file, format);
+        }
+        let path = self.store_file(endpoint, body, headers).await?;
+        if self.config.validate_only {
+            return Ok(asset_name(stem, &file_digest(&path)?, format));
+        }
+        let name = copy_asset(&self.assets_dir(), stem, &path, format)?;
+        self.written_assets
+            .lock()
+            .expect("written assets lock poisoned")
+            .insert(name.clone());
+        Ok(name)
+    }
+
+    /// Checks that kroki supports an output format for a diagram type, if `capabilities_url` is set.
+    ///
+    /// Only requests to the book's `endpoint` are checked, since the capabilities are fetched from there.
+    async fn check_format(&self, endpoint: &str, diagram_type: &str, format: &str) -> Result<()> {
+        let Some(url) = &self.config.capabilities_url else {
+            return Ok(());
+        };
+        if endpoint != self.config.endpoint {
+            return Ok(());
+        }
+        let capabilities = self
+            .capabilities
+            .get_or_init(|| async {
+                let url = match reqwest::Url::parse(&self.config.endpoint).and_then(|e| e.join(url))
+                {
+                    Ok(url) => url.to_string(),
+                    Err(_) => url.clone(),
+                };
+                let headers = self
+                    .config
+                    .headers
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        Some((
+                            HeaderName::from_bytes(name.as_bytes()).ok()?,
+                            HeaderValue::from_str(value).ok()?,
+                        ))
+                    })
+                    .collect();
+                Capabilities::fetch(&self.client, &url, headers).await
+            })
+            .await;
+        match capabilities {
+            Some(capabilities) => capabilities.check(diagram_type, format),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until another kroki request is allowed, if their concurrency is limited.
+    ///
+    /// A slot for the diagram's type is taken first, so requests waiting on their type don't hold up others.
+    async fn acquire(&self, diagram_type: &str) -> Permits<'_> {
+        let diagram_type = self.type_limits.acquire(diagram_type).await;
+        let request = match &self.concurrency {
+            Some(concurrency) => Some(concurrency.acquire().await),
+            None => None,
+        };
+        Permits {
+            _diagram_type: diagram_type,
+            _request: request,
+        }
+    }
+
+    /// Frees a kroki request's slots after it succeeds, which may let more requests through when ramping up.
+    fn succeeded(&self, permits: Permits) {
+        drop(permits);
+        if let Some(concurrency) = &self.concurrency {
+            concurrency.succeeded();
+        }
+    }
+
+    /// Turns a rendered SVG into the markup that goes in the chapter.
+    ///
+    /// Inline SVGs are wrapped in a `wrapper_tag`, unless `wrap_svg` is off and the type isn't in
+    /// `wrap_svg_types`, and `responsive` strips their size and wraps them in a `<div>` too. A `<figure>`
+    /// gets `caption`, which must already be escaped, as its `<figcaption>`. In file mode the SVG is
+    /// written to the assets directory and referenced with an `<img>` tag, which `link_to_full` wraps
+    /// in a link to the file. Files are named by `stem` if there is one, and by a hash of their
+    /// contents otherwise.
+    ///
+    /// With `output = "auto-object"`, SVGs are inlined unless they're over `auto_object_bytes` and contain
+    /// one of the `auto_object_markers`, in which case they're written to a file and embedded with an `<object>`.
+    ///
+    /// SVGs that would be inlined but are over `max_svg_bytes` are handled as `oversize` says.
+    fn place(
+        &self,
+        mut svg: String,
+        diagram_type: &str,
+        stem: Option<&str>,
+        label: Option<&str>,
+        caption: Option<&str>,
+        chapter_path: Option<&Path>,
+    ) -> Result<String> {
+        let inline = match self.config.output {
+            Output::AutoObject
+                if svg.len() > self.config.auto_object_bytes
+                    && self
+                        .config
+                        .auto_object_markers
+                        .iter()
+                        .any(|marker| svg.contains(marker.as_str())) =>
+            {
+                let name = self.write_asset(stem, svg::as_file(&svg).as_bytes(), "svg")?;
+                let url = self.asset_url(chapter_path, &name)?;
+                return Ok(self.file_element(&url, Some("image/svg+xml"), label));
+            }
+            Output::Files => return self.place_svg_file(svg, stem, label, chapter_path),
+            Output::Inline | Output::AutoObject => {
+                if let Some(max) = self.config.max_svg_bytes.filter(|&max| svg.len() > max) {
+                    let message = format!(
+                        "a {diagram_type} diagram in {} is {} bytes, over max_svg_bytes ({max})",
+                        chapter_name(chapter_path),
+                        svg.len()
+                    );
+                    match self.config.oversize {
+                        Oversize::Warn => log::warn!("{message}"),
+                        Oversize::Img => {
+                            log::warn!("{message}, so it was written to a file instead of inlined");
+                            return self.place_svg_file(svg, stem, label, chapter_path);
+                        }
+                        Oversize::Fail => bail!("{message}"),
+                    }
+                }
+                if self.config.responsive {
+                    svg::make_responsive(&mut svg);
+                }
+                if self.config.wrap_svg
+                    || self.config.wrap_svg_types.iter().any(|t| t == diagram_type)
+                {
+                    self.wrap(&svg, caption)
+                } else {
+                    svg::as_html_block(&svg)
+                }
+            }
+        };
+        if self.config.responsive {
+            Ok(format!(
+                "<div class=\"kroki-responsive\" style=\"max-width: 100%\">\n{inline}\n</div>"
+            ))
+        } else {
+            Ok(inline)
+        }
+    }
+
+    /// Writes an SVG to the assets directory and references it with an `<img>` tag.
+    fn place_svg_file(
+        &self,
+        svg: String,
+        stem: Option<&str>,
+        label: Option<&str>,
+        chapter_path: Option<&Path>,
+    ) -> Result<String> {
+        let svg = if self.config.csp_safe {
+            svg::strip_scripts(&svg)
+        } else {
+            svg
+        };
+        let name = self.write_asset(stem, svg::as_file(&svg).as_bytes(), "svg")?;
+        let url = self.asset_url(chapter_path, &name)?;
+        Ok(self.file_element(&url, None, label))
+    }
+
+    /// Wraps an inlined SVG in the `wrapper_tag`, with the `wrapper_class`.
+    ///
+    /// A `<pre>` keeps the SVG as-is, since blank lines don't end it. Other tags end at a blank line, so the
+    /// SVG is made into an HTML block of its own inside them.
+    fn wrap(&self, svg: &str, caption: Option<&str>) -> String {
+        let class = match &self.config.wrapper_class {
+            Some(class) => format!(r#" class="{}""#, escape_html(class)),
+            None => String::new(),
+        };
+        match self.config.wrapper_tag {
+            WrapperTag::Pre => format!("<pre{class}>{svg}</pre>"),
+            WrapperTag::Div => format!("<div{class}>\n{}\n</div>", svg::as_html_block(svg)),
+            WrapperTag::Figure => {
+                let caption = caption
+                    .map(|caption| format!("\n<figcaption>{caption}</figcaption>"))
+                    .unwrap_or_default();
+                format!(
+                    "<figure{class}>\n{}{caption}\n</figure>",
+                    svg::as_html_block(svg)
+                )
+            }
+        }
+    }
+
+    /// Inlines a diagram rendered in a format other than SVG, and returns the markup for the chapter.
+    ///
+    /// Text formats are put in a `<pre>` tag, and MathML as markup. Images and PDFs are inlined as `data:` URIs;
+    /// with `output = "files"` they're streamed to the assets directory instead, and never get here.
+    fn place_file(&self, file: &[u8], format: &str, label: Option<&str>) -> Result<String> {
+        match format {
+            "txt" | "utxt" => {
+                return Ok(format!(
+                    r#"<pre class="kroki-text">{}</pre>"#,
+                    escape_html(&String::from_utf8_lossy(file))
+                ))
+            }
+            "mathml" => return mathml_block(file),
+            _ => {}
+        }
+        let mime = file_mime(format)?;
+        let url = format!(
+            "data:{mime};base64,{}",
+            base64::engine::general_purpose::STANDARD.encode(file)
+        );
+        Ok(self.file_element(&url, (format == "pdf").then_some(mime), label))
+    }
+
+    /// Markup that shows a diagram from a URL: an `<img>`, or an `<object>` of the given type.
+    ///
+    /// `label` must already be escaped, and becomes the `<img>`'s `alt` or the `<object>`'s `aria-label`.
+    ///
+    /// It's wrapped in a link to the URL with `link_to_full`, and in a shimmer that shows until it loads
+    /// with `loading_placeholder`.
+    fn file_element(&self, url: &str, object_type: Option<&str>, label: Option<&str>) -> String {
+        let onload = if self.config.loading_placeholder && !self.config.csp_safe {
+            r#" onload="this.parentNode.classList.remove('kroki-loading')""#
+        } else {
+            ""
+        };
+        let mut element = match (object_type, label) {
+            (Some(object_type), Some(label)) => format!(
+                r#"<object data="{url}" type="{object_type}" role="img" aria-label="{label}"{onload}></object>"#
+            ),
+            (Some(object_type), None) => {
+                format!(r#"<object data="{url}" type="{object_type}"{onload}></object>"#)
+            }
+            (None, Some(label)) => format!(r#"<img src="{url}" alt="{label}"{onload}>"#),
+            (None, None) => format!(r#"<img src="{url}"{onload}>"#),
+        };
+        if !onload.is_empty() {
+            element = format!(r#"<span class="kroki-loading">{element}</span>"#);
+        }
+        if self.config.link_to_full {
+            element = format!(r#"<a href="{url}" target="_blank">{element}</a>"#);
+        }
+        element
+    }
+
+    /// Renders a diagram in extra formats, writes them to the assets directory,
+    /// and returns a block of links to download them.
+    async fn download_links(
+        &self,
+        body: &KrokiRequestBody,
+        headers: &HeaderMap,
+        formats: &[String],
+        stem: Option<&str>,
+        chapter_path: Option<&Path>,
+    ) -> Result<String> {
+        let endpoint = self.config.endpoint_for(&body.diagram_type);
+        let link_futures = formats.iter().map(|format| async move {
+            let body = KrokiRequestBody {
+                output_format: format.clone(),
+                ..body.clone()
+            };
+            let name = self
+                .render_file_to_assets(endpoint, &body, headers, stem)
+                .await?;
+            let url = self.asset_url(chapter_path, &name)?;
+            Ok(format!(
+                r#"<a href="{url}" download>{}</a>"#,
+                format.to_uppercase()
+            ))
+        });
+        let links = futures::future::join_all(link_futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(format!(
+            r#"<div class="kroki-downloads">Download: {}</div>"#,
+            links.join(" ")
+        ))
+    }
+
+    /// Headers to send with a diagram's requests.
+    ///
+    /// The book-wide `headers` are merged with the `type_headers` for the diagram's type,
+    /// then with the diagram's own `header-<name>` attributes and its `option-<name>` diagram options. When a
+    /// header is set in more than one layer, the most specific one wins.
+    fn headers_for(&self, diagram: &Diagram, diagram_type: &str) -> Result<HeaderMap> {
+        let book_headers = self.config.headers.iter();
+        let type_headers = self
+            .config
+            .type_headers
+            .get(diagram_type)
+            .into_iter()
+            .flatten();
+        let diagram_headers = diagram
+            .attributes
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("header-")?, value)));
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in book_headers
+            .map(|(name, value)| (name.as_str(), value))
+            .chain(type_headers.map(|(name, value)| (name.as_str(), value)))
+            .chain(diagram_headers)
+        {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name `{name}`"))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("invalid value for header `{name}`"))?;
+            headers.insert(name, value);
+        }
+
+        let options = diagram
+            .attributes
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("option-")?, value)))
+            .map(|(name, value)| {
+                check_diagram_option(name, value)?;
+                Ok((name.to_string(), value.clone()))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        with_diagram_options(&headers, &options)
+    }
+
+    /// URL that a chapter links to a diagram file with.
+    ///
+    /// Files are linked from `asset_url_prefix` if it's set, and relative to the chapter otherwise.
+    fn asset_url(&self, chapter_path: Option<&Path>, name: &str) -> Result<String> {
+        match &self.config.asset_url_prefix {
+            Some(prefix) => Ok(format!("{}/{name}", prefix.trim_end_matches('/'))),
+            None => relative_url(
+                chapter_path,
+                self.config.site_base_url.as_deref(),
+                &self.config.assets_dir,
+                name,
+            ),
+        }
+    }
+
+    /// The stem that a diagram's `out` attribute names its file with, as a path relative to the assets directory.
+    ///
+    /// The path must stay inside the assets directory, and its extension must match the diagram's format.
+    fn out_stem(&self, out: &str, format: &str) -> Result<String> {
+        if self.config.output != Output::Files {
+            bail!(r#"the `out` attribute requires output = "files""#);
+        }
+        let path = Path::new(out);
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("`out` path {out} must be relative to the assets directory and stay inside it");
+        }
+        match out.strip_suffix(&format!(".{format}")) {
+            Some(stem) if !stem.is_empty() => Ok(stem.to_string()),
+            _ => bail!("`out` path {out} must end in .{format} to match the diagram's format"),
+        }
+    }
+
+    /// Writes a file to the assets directory, keeping track of it for `check_unused_assets`.
+    fn write_asset(&self, stem: Option<&str>, contents: &[u8], extension: &str) -> Result<String> {
+        if self.config.validate_only {
+            return Ok(asset_name(stem, &svg::hex_digest(contents), extension));
+        }
+        let name = write_asset(&self.assets_dir(), stem, contents, extension)?;
+        self.written_assets
+            .lock()
+            .expect("written assets lock poisoned")
+            .insert(name.clone());
+        Ok(name)
+    }
+
+    /// Absolute path of the directory that diagram files are written to.
+    fn assets_dir(&self) -> PathBuf {
+        self.roots.source.join(&self.config.assets_dir)
+    }
+
+    /// Writes a diagnostic about a chapter as a line of JSON, if `diagnostics_format` is `json`.
+    ///
+    /// `range` is the byte range of the diagram in the chapter's content, if the diagnostic is about one.
+    fn diagnostic(
+        &self,
+        chapter_path: Option<&Path>,
+        range: Option<Range<usize>>,
+        severity: &str,
+        message: &str,
+    ) {
+        let Some(diagnostics) = &self.diagnostics else {
+            return;
+        };
+        let line = serde_json::json!({
+            "chapter": chapter_path,
+            "range": range.map(|range| [range.start, range.end]),
+            "severity": severity,
+            "message": message,
+        });
+        let mut diagnostics = diagnostics.lock().expect("diagnostics lock poisoned");
+        if let Err(error) = writeln!(diagnostics, "{line}") {
+            log::warn!("could not write diagnostic: {error}");
+        }
+    }
+
+    /// Writes a request body as a line of JSON, along with where it came from and where it would have gone.
+    fn dump_request(
+        &self,
+        dump: &Mutex<Box<dyn Write + Send>>,
+        chapter_path: Option<&Path>,
+        body: &KrokiRequestBody,
+    ) -> Result<()> {
+        let line = serde_json::json!({
+            "chapter": chapter_path,
+            "endpoint": self.config.endpoint_for(&body.diagram_type),
+            "body": body,
+        });
+        let mut dump = dump.lock().expect("request dump lock poisoned");
+        writeln!(dump, "{line}")?;
+        Ok(())
+    }
+}
+
+/// Shows the screen or print variant of each diagram depending on the media.
+const VARIANT_STYLE: &str = "<style>\n\
+    @media print { .kroki-screen { display: none; } }\n\
+    @media not print { .kroki-print { display: none; } }\n\
+    </style>";
+
+/// Shows the dark variant of diagrams with mdbook's dark themes, and the light one otherwise and when printing.
+const THEME_STYLE: &str = "<style>\n\
+    .kroki-dark { display: none; }\n\
+    html.coal .kroki-dark, html.navy .kroki-dark, html.ayu .kroki-dark { display: block; }\n\
+    html.coal .kroki-light, html.navy .kroki-light, html.ayu .kroki-light { display: none; }\n\
+    @media print { .kroki-light { display: block !important; } .kroki-dark { display: none !important; } }\n\
+    </style>";
+
+/// Hides search text visually while keeping it in the page for mdbook's search index and screen readers.
+const SEARCH_TEXT_STYLE: &str = "<style>\n\
+    .kroki-search-text { position: absolute; width: 1px; height: 1px; overflow: hidden; clip: rect(0 0 0 0); white-space: nowrap; }\n\
+    </style>";
+
+/// Checks that a diagram's `option-<name>` attribute is a simple option kroki can take as a header.
+fn check_diagram_option(name: &str, value: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid diagram option name `{name}`: use letters, digits, `-`, and `_`");
+    }
+    if value.is_empty() || value.chars().any(char::is_control) {
+        bail!("diagram option `{name}` must be a non-empty value on a single line");
+    }
+    Ok(())
+}
+
+/// Adds kroki diagram options to a set of headers, as `Kroki-Diagram-Options-<name>` headers.
+fn with_diagram_options(
+    headers: &HeaderMap,
+    options: &HashMap<String, String>,
+) -> Result<HeaderMap> {
+    let mut headers = headers.clone();
+    for (name, value) in options {
+        let header = format!("Kroki-Diagram-Options-{name}");
+        let header = HeaderName::from_bytes(header.as_bytes())
+            .with_context(|| format!("invalid diagram option name `{name}`"))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("invalid value for diagram option `{name}`"))?;
+        headers.insert(header, value);
+    }
+    Ok(headers)
+}
+
+/// Shimmers behind diagram files until they load. Browsers without scripting never remove it, so it's left out there.
+const LOADING_STYLE: &str = "<style>\n\
+    .kroki-loading { display: inline-block; min-width: 8em; min-height: 6em; background: linear-gradient(90deg, rgba(128, 128, 128, 0.1) 25%, rgba(128, 128, 128, 0.25) 50%, rgba(128, 128, 128, 0.1) 75%); background-size: 200% 100%; animation: kroki-shimmer 1.5s linear infinite; }\n\
+    @keyframes kroki-shimmer { from { background-position: 100% 0; } to { background-position: -100% 0; } }\n\
+    @media (scripting: none) { .kroki-loading { min-width: 0; min-height: 0; background: none; animation: none; } }\n\
+    </style>";
+
+/// Lays out the default gallery markup as a grid of captioned diagrams.
+const GALLERY_STYLE: &str = "<style>\n\
+    .kroki-gallery { display: grid; grid-template-columns: repeat(auto-fill, minmax(16em, 1fr)); gap: 1em; align-items: end; }\n\
+    .kroki-gallery-item { margin: 0; text-align: center; }\n\
+    </style>";
+
+/// Markup for a MathML diagram: its `<math>` element, without kroki's prolog, in a `<div class="kroki-mathml">`.
+fn mathml_block(file: &[u8]) -> Result<String> {
+    let response = std::str::from_utf8(file).context("kroki's MathML isn't UTF-8")?;
+    // Without blank lines, the whole element stays in the HTML block the `<div>` starts.
+    let math = extract_mathml(response)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(format!("<div class=\"kroki-mathml\">\n{math}\n</div>"))
+}
+
+/// The MIME type of an image or PDF diagram format that's shown from a URL.
+fn file_mime(format: &str) -> Result<&'static str> {
+    Ok(match format {
+        "png" => "image/png",
+        "jpeg" => "image/jpeg",
+        "pdf" => "application/pdf",
+        _ => bail!(
+            "can't put {format} diagrams in chapters; use svg, png, jpeg, pdf, txt, utxt, or mathml"
+        ),
+    })
+}
+
+/// Escapes text to be shown as-is in HTML.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `1 diagram` or `<count> diagrams`, for messages.
+fn diagram_count(count: usize) -> String {
+    match count {
+        1 => "1 diagram".to_string(),
+        count => format!("{count} diagrams"),
+    }
+}
+
+/// The line of a chapter that a byte offset into it is on, counting from 1.
+fn line_at(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+/// Names a chapter in messages.
+pub fn chapter_name(chapter_path: Option<&Path>) -> String {
+    chapter_path.map_or_else(
+        || "a chapter with no source path".to_string(),
+        |path| path.display().to_string(),
+    )
+}
+
+/// Widens a diagram's replacement so it's separated from the markdown above and below it by blank lines.
+///
+/// Only diagrams with their lines to themselves are padded, and not against HTML, like a `<div>` around
+/// the diagram, since a blank line would end the HTML block.
+fn pad_blank_lines(content: &str, range: &Range<usize>, output: String) -> (Range<usize>, String) {
+    let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[range.end..]
+        .find('\n')
+        .map_or(content.len(), |i| range.end + i);
+    let indent = &content[line_start..range.start];
+    if !indent.trim().is_empty() || !content[range.end..line_end].trim().is_empty() {
+        return (range.clone(), output);
+    }
+    let needs_padding = |line: Option<&str>| {
+        line.is_some_and(|line| !line.trim().is_empty() && !line.trim_start().starts_with('<'))
+    };
+
+    let mut range = range.clone();
+    let mut output = output;
+    let above = content[..line_start.saturating_sub(1)]
+        .rsplit('\n')
+        .next()
+        .filter(|_| line_start > 0);
+    if needs_padding(above) {
+        output = format!("\n{indent}{output}");
+        range.start = line_start;
+    }
+    let below = content
+        .get(line_end + 1..)
+        .map(|rest| rest.split('\n').next().unwrap_or(""));
+    if needs_padding(below) {
+        output.push('\n');
+    }
+    (range, output)
+}
+
+/// Shrinks a range to exclude whitespace at either end of the content it covers.
+fn trim_range(content: &str, range: &Range<usize>) -> Range<usize> {
+    let covered = &content[range.clone()];
+    let start = range.start + (covered.len() - covered.trim_start().len());
+    let end = range.end - (covered.len() - covered.trim_end().len());
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replaces the diagram in `content` with `<svg/>`, padded like `pad_blank_lines = true` does.
+    fn replace_padded(content: &str) -> String {
+        let start = content.find("```kroki").unwrap();
+        let end = content.rfind("```").unwrap() + 3;
+        let (range, output) = pad_blank_lines(content, &(start..end), "<svg/>".to_string());
+        let mut content = content.to_string();
+        content.replace_range(range, &output);
+        content
+    }
+
+    #[test]
+    fn pads_diagram_after_heading() {
+        assert_eq!(
+            replace_padded("# Title\n```kroki-graphviz\na -> b\n```\nText after.\n"),
+            "# Title\n\n<svg/>\n\nText after.\n"
+        );
+    }
+
+    #[test]
+    fn pads_diagram_between_list_items() {
+        assert_eq!(
+            replace_padded("- one\n- two\n```kroki-graphviz\na -> b\n```\n- three\n"),
+            "- one\n- two\n\n<svg/>\n\n- three\n"
+        );
+    }
+
+    #[test]
+    fn pads_diagram_inside_paragraph() {
+        assert_eq!(
+            replace_padded("Some text.\n```kroki-graphviz\na -> b\n```\nMore text.\n"),
+            "Some text.\n\n<svg/>\n\nMore text.\n"
+        );
+    }
+
+    #[test]
+    fn embeds_mathml_without_its_prolog() {
+        let response = concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<!DOCTYPE math PUBLIC \"-//W3C//DTD MathML 2.0//EN\" ",
+            "\"http://www.w3.org/Math/DTD/mathml2/mathml2.dtd\">\n",
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\">\n",
+            "  <msup><mi>e</mi><mrow><mi>i</mi><mi>\u{3c0}</mi></mrow></msup>\n",
+            "\n",
+            "  <mo>+</mo><mn>1</mn><mo>=</mo><mn>0</mn>\n",
+            "</math>\n",
+        );
+        let block = mathml_block(response.as_bytes()).unwrap();
+        assert_eq!(
+            block,
+            concat!(
+                "<div class=\"kroki-mathml\">\n",
+                "<math xmlns=\"http://www.w3.org/1998/Math/MathML\">\n",
+                "  <msup><mi>e</mi><mrow><mi>i</mi><mi>\u{3c0}</mi></mrow></msup>\n",
+                "  <mo>+</mo><mn>1</mn><mo>=</mo><mn>0</mn>\n",
+                "</math>\n",
+                "</div>",
+            )
+        );
+        assert!(
+            !block.contains("<?xml") && !block.contains("<!DOCTYPE") && !block.contains("<pre")
+        );
+        assert!(!block.contains("\n\n"));
+    }
+}
@@ -0,0 +1,741 @@
+use anyhow::{bail, Result};
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// Moves the `<style>` elements out of a chapter's rendered diagrams into one stylesheet for the chapter.
+///
+/// Each diagram gets a class derived from a hash of its styles, and its rules are scoped to that class
+/// so they can't bleed into other diagrams on the page. Diagrams with identical styles share a class,
+/// so their rules are only emitted once. Returns `None` if no diagram had any styles.
+pub fn hoist_styles(outputs: &mut [String]) -> Option<String> {
+    let mut seen = HashSet::new();
+    let mut sheets = Vec::new();
+
+    for output in outputs.iter_mut() {
+        let css = take_styles(output);
+        if css.trim().is_empty() {
+            continue;
+        }
+        let class = format!("kroki-style-{}", &hex_digest(css.as_bytes())[..12]);
+        add_root_class(output, &class);
+        if seen.insert(class.clone()) {
+            let root_id = root_attribute(output, "id").map(|(_, value)| output[value].to_string());
+            sheets.push(scope_css(&css, &format!("svg.{class}"), root_id.as_deref()));
+        }
+    }
+
+    if sheets.is_empty() {
+        None
+    } else {
+        Some(format!("<style>\n{}</style>", sheets.concat()))
+    }
+}
+
+/// Moves every SVG in a chapter's rendered diagrams into one sprite sheet of `<symbol>`s for the chapter,
+/// leaving an `<svg>` that `<use>`s its symbol in place of each.
+///
+/// Ids inside each symbol are prefixed with the symbol's id so they can't collide with another diagram's.
+/// The root's own id moves to a group inside the symbol, so styles that select it still apply.
+/// Returns `None` if there were no SVGs.
+pub fn sprite_sheet(outputs: &mut [String]) -> Option<String> {
+    let mut symbols = String::new();
+    let mut count = 0;
+
+    for output in outputs.iter_mut() {
+        let mut search_from = 0;
+        while let Some(range) = next_svg(output, search_from) {
+            let svg = &output[range.clone()];
+            let Some(tag) = root_tag(svg) else {
+                break;
+            };
+            let attribute = |name| root_attribute(svg, name).map(|(_, value)| &svg[value]);
+
+            let id = format!("kroki-sprite-{count}");
+            count += 1;
+            let inner = namespace_ids(&svg[tag.end..svg.len() - "</svg>".len()], &format!("{id}-"));
+            let contents = match attribute("id") {
+                Some(root_id) => format!(r#"<g id="{root_id}">{inner}</g>"#),
+                None => inner,
+            };
+            let mut symbol = format!(r#"<symbol id="{id}""#);
+            let mut placeholder = String::from("<svg");
+            for name in ["viewBox", "preserveAspectRatio"] {
+                if let Some(value) = attribute(name) {
+                    symbol.push_str(&format!(r#" {name}="{value}""#));
+                }
+            }
+            for name in [
+                "class",
+                "style",
+                "width",
+                "height",
+                "viewBox",
+                "preserveAspectRatio",
+            ] {
+                if let Some(value) = attribute(name) {
+                    placeholder.push_str(&format!(r#" {name}="{value}""#));
+                }
+            }
+            symbols.push_str(&format!("{symbol}>{contents}</symbol>"));
+            placeholder.push_str(&format!(r##"><use href="#{id}"/></svg>"##));
+
+            output.replace_range(range.clone(), &placeholder);
+            search_from = range.start + placeholder.len();
+        }
+    }
+
+    (count > 0).then(|| {
+        as_html_block(&format!(
+            r#"<svg class="kroki-sprites" xmlns="http://www.w3.org/2000/svg" style="display: none">{symbols}</svg>"#
+        ))
+    })
+}
+
+/// Prefixes every id defined in some SVG markup, along with the `href="#..."` and `url(#...)` references to them
+/// and `#id` selectors in its `<style>` elements.
+///
+/// Only `id` attributes and references to the SVG's own ids are touched, so links elsewhere (like the
+/// `<a xlink:href="..." target="..." title="...">` links in clickable diagrams) keep working. Attributes are
+/// only read inside tags, so text that happens to look like one is left alone.
+pub fn namespace_ids(svg: &str, prefix: &str) -> String {
+    static TAGS: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTES: OnceLock<Regex> = OnceLock::new();
+    static URLS: OnceLock<Regex> = OnceLock::new();
+    static STYLES: OnceLock<Regex> = OnceLock::new();
+    static SELECTORS: OnceLock<Regex> = OnceLock::new();
+
+    let tags = TAGS.get_or_init(|| Regex::new(r#"<[a-zA-Z](?:[^>"']|"[^"]*"|'[^']*')*>"#).unwrap());
+    let attributes = ATTRIBUTES
+        .get_or_init(|| Regex::new(r#"(\s([\w:.-]+)\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap());
+    let urls = URLS.get_or_init(|| Regex::new(r#"(url\(\s*["']?#)([^"')\s]+)"#).unwrap());
+    // An attribute's name, the quote its value is in, and its value.
+    let attribute = |captures: &Captures| {
+        let (quote, value) = match captures.get(3) {
+            Some(value) => ('"', value.as_str().to_string()),
+            None => ('\'', captures[4].to_string()),
+        };
+        (captures[2].to_string(), quote, value)
+    };
+
+    let ids = tags
+        .find_iter(svg)
+        .flat_map(|tag| attributes.captures_iter(tag.as_str()))
+        .map(|captures| attribute(&captures))
+        .filter(|(name, _, _)| name == "id")
+        .map(|(_, _, value)| value)
+        .collect::<HashSet<_>>();
+    if ids.is_empty() {
+        return svg.to_string();
+    }
+    let svg = tags.replace_all(svg, |tag: &Captures| {
+        attributes
+            .replace_all(&tag[0], |captures: &Captures| {
+                let (name, quote, value) = attribute(captures);
+                let reference = value.strip_prefix('#').filter(|id| ids.contains(*id));
+                match (name.as_str(), reference) {
+                    ("id", _) => format!("{}{quote}{prefix}{value}{quote}", &captures[1]),
+                    ("href" | "xlink:href", Some(id)) => {
+                        format!("{}{quote}#{prefix}{id}{quote}", &captures[1])
+                    }
+                    _ => captures[0].to_string(),
+                }
+            })
+            .into_owned()
+    });
+    let prefix_reference = |captures: &Captures| {
+        if ids.contains(&captures[2]) {
+            format!("{}{prefix}{}", &captures[1], &captures[2])
+        } else {
+            captures[0].to_string()
+        }
+    };
+    let svg = urls.replace_all(&svg, prefix_reference);
+
+    let styles = STYLES.get_or_init(|| Regex::new(r"(?s)(<style[^>]*>)(.*?)(</style>)").unwrap());
+    let selectors = SELECTORS.get_or_init(|| Regex::new(r"(#)(-?[_a-zA-Z][\w-]*)").unwrap());
+    styles
+        .replace_all(&svg, |captures: &Captures| {
+            format!(
+                "{}{}{}",
+                &captures[1],
+                selectors.replace_all(&captures[2], prefix_reference),
+                &captures[3]
+            )
+        })
+        .into_owned()
+}
+
+/// Byte range of the next top-level `<svg>` element in some markup, searching from `from`.
+fn next_svg(markup: &str, from: usize) -> Option<Range<usize>> {
+    let start = from + markup[from..].find("<svg")?;
+    let mut depth = 0;
+    let mut i = start;
+    loop {
+        let open = markup[i..].find("<svg").map(|found| i + found);
+        let close = i + markup[i..].find("</svg>")?;
+        match open {
+            Some(open) if open < close => {
+                depth += 1;
+                i = open + "<svg".len();
+            }
+            _ => {
+                depth -= 1;
+                i = close + "</svg>".len();
+                if depth == 0 {
+                    return Some(start..i);
+                }
+            }
+        }
+    }
+}
+
+/// Removes `<script>` elements, event handler attributes, and `javascript:` links from an SVG.
+pub fn strip_scripts(svg: &str) -> String {
+    static SCRIPTS: OnceLock<Regex> = OnceLock::new();
+    static HANDLERS: OnceLock<Regex> = OnceLock::new();
+    static JAVASCRIPT_LINKS: OnceLock<Regex> = OnceLock::new();
+
+    let scripts = SCRIPTS
+        .get_or_init(|| Regex::new(r"(?is)<script\b[^>]*/>|<script\b.*?</script\s*>").unwrap());
+    let handlers =
+        HANDLERS.get_or_init(|| Regex::new(r#"(?i)\s+on[a-z]+\s*=\s*("[^"]*"|'[^']*')"#).unwrap());
+    let javascript_links = JAVASCRIPT_LINKS.get_or_init(|| {
+        Regex::new(r#"(?i)\s+(xlink:)?href\s*=\s*("\s*javascript:[^"]*"|'\s*javascript:[^']*')"#)
+            .unwrap()
+    });
+
+    let svg = scripts.replace_all(svg, "");
+    let svg = handlers.replace_all(&svg, "");
+    javascript_links.replace_all(&svg, "").into_owned()
+}
+
+/// Checks that an SVG is well-formed XML, so malformed output doesn't end up in the book.
+pub fn validate(svg: &str) -> Result<()> {
+    match xmltree::Element::parse(svg.as_bytes()) {
+        Ok(_) => Ok(()),
+        // Displays as `row:column message`.
+        Err(xmltree::ParseError::MalformedXml(error)) => bail!("invalid SVG at {error}"),
+        Err(error) => bail!("invalid SVG: {error}"),
+    }
+}
+
+/// The contents of a standalone `.svg` file for an SVG element, which starts with an XML declaration.
+///
+/// Rendered SVGs have any prolog cut off so they can be inlined into HTML, where a declaration isn't allowed.
+/// Kroki's doctypes point at the SVG 1.1 DTD, which SVG files don't need, so only the declaration is put back.
+pub fn as_file(svg: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{svg}\n")
+}
+
+/// Formats an SVG so markdown passes it through untouched without a wrapper element.
+///
+/// The root `<svg>` tag gets a line to itself, which starts an HTML block,
+/// and blank lines are removed since they would end the block early.
+pub fn as_html_block(svg: &str) -> String {
+    let mut svg = svg
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if let Some(tag) = root_tag(&svg) {
+        let flattened = svg[tag.clone()].replace(['\r', '\n'], " ");
+        svg.replace_range(tag.clone(), &format!("{flattened}\n"));
+        svg.insert(tag.start, '\n');
+    }
+    svg.trim().to_string()
+}
+
+/// Lowercase hex SHA-256 of some bytes.
+pub fn hex_digest(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// CSS that reserves a diagram's space before it's painted: the aspect ratio of its `viewBox`,
+/// capped at its own width if it has one.
+///
+/// Returns `None` if the SVG doesn't have a usable `viewBox`.
+pub fn reserved_space(svg: &str) -> Option<String> {
+    let (_, view_box) = root_attribute(svg, "viewBox")?;
+    let numbers = svg[view_box]
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|number| !number.is_empty())
+        .map(str::parse::<f64>)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    let [_, _, width, height] = numbers[..] else {
+        return None;
+    };
+    if !(width > 0.0 && height > 0.0) {
+        return None;
+    }
+
+    let mut style = format!("aspect-ratio: {width} / {height}");
+    if let Some((_, value)) = root_attribute(svg, "width") {
+        let max_width = &svg[value];
+        if !max_width.is_empty()
+            && max_width
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.')
+        {
+            let unit = if max_width.ends_with(|c: char| c.is_ascii_digit()) {
+                "px"
+            } else {
+                ""
+            };
+            style.push_str(&format!("; max-width: {max_width}{unit}"));
+        }
+    }
+    Some(style)
+}
+
+/// Removes the fixed `width` and `height` from the root `<svg>`, so it scales to its container. They're removed from
+/// its `style` too, like PlantUML sets them, and the rest of the style is kept.
+///
+/// SVGs without a `viewBox` are left alone, since they'd lose their aspect ratio.
+pub fn make_responsive(svg: &mut String) {
+    if root_attribute(svg, "viewBox").is_none() {
+        return;
+    }
+    if let Some((attribute, value)) = root_attribute(svg, "style") {
+        let declarations = svg[value.clone()]
+            .split(';')
+            .map(str::trim)
+            .filter(|declaration| {
+                let property = declaration.split(':').next().unwrap_or_default().trim();
+                !declaration.is_empty()
+                    && !property.eq_ignore_ascii_case("width")
+                    && !property.eq_ignore_ascii_case("height")
+            })
+            .collect::<Vec<_>>();
+        if declarations.is_empty() {
+            svg.replace_range(attribute, "");
+        } else {
+            svg.replace_range(value, &format!("{};", declarations.join(";")));
+        }
+    }
+    let mut attributes = ["width", "height"]
+        .iter()
+        .filter_map(|name| root_attribute(svg, name))
+        .map(|(attribute, _)| attribute)
+        .collect::<Vec<_>>();
+    attributes.sort_by_key(|attribute| std::cmp::Reverse(attribute.start));
+    for attribute in attributes {
+        svg.replace_range(attribute, "");
+    }
+}
+
+/// Inserts a `<title>` as the first child of the root `<svg>`, which browsers show as a tooltip.
+///
+/// `title` must already be escaped. SVGs that already have a root `<title>` are left alone.
+pub fn add_title(svg: &mut String, title: &str) {
+    let has_title =
+        xmltree::Element::parse(svg.as_bytes()).is_ok_and(|root| root.get_child("title").is_some());
+    if let (false, Some(tag)) = (has_title, root_tag(svg)) {
+        svg.insert_str(tag.end, &format!("<title>{title}</title>"));
+    }
+}
+
+/// Marks the root `<svg>` as an image labelled with `label`, for screen readers.
+///
+/// `label` must already be escaped. A `role` or `aria-label` the SVG already has is kept.
+pub fn add_label(svg: &mut String, label: &str) {
+    let mut attributes = String::new();
+    if root_attribute(svg, "role").is_none() {
+        attributes.push_str(r#" role="img""#);
+    }
+    if root_attribute(svg, "aria-label").is_none() {
+        attributes.push_str(&format!(r#" aria-label="{label}""#));
+    }
+    if let Some(tag) = root_tag(svg) {
+        svg.insert_str(tag.start + "<svg".len(), &attributes);
+    }
+}
+
+/// Byte range of the root `<svg ...>` start tag.
+fn root_tag(svg: &str) -> Option<Range<usize>> {
+    let start = svg.find("<svg")?;
+    let mut quote = None;
+    for (i, c) in svg[start..].char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(start..start + i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Finds an attribute on the root `<svg>` tag.
+///
+/// Returns the byte range of the whole attribute (including its leading whitespace),
+/// and the range of its unquoted value.
+fn root_attribute(svg: &str, name: &str) -> Option<(Range<usize>, Range<usize>)> {
+    let tag = root_tag(svg)?;
+    let bytes = svg.as_bytes();
+    let mut i = tag.start + "<svg".len();
+
+    while i < tag.end {
+        let attribute_start = i;
+        while i < tag.end && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let name_start = i;
+        while i < tag.end
+            && !matches!(bytes[i], b'=' | b'>' | b'/')
+            && !bytes[i].is_ascii_whitespace()
+        {
+            i += 1;
+        }
+        let name_end = i;
+        if name_start == name_end {
+            i += 1;
+            continue;
+        }
+
+        let mut value = name_end..name_end;
+        if bytes.get(i) == Some(&b'=') {
+            i += 1;
+            match bytes[i] {
+                quote @ (b'"' | b'\'') => {
+                    let end = svg[i + 1..tag.end].find(quote as char)? + i + 1;
+                    value = i + 1..end;
+                    i = end + 1;
+                }
+                _ => {
+                    let start = i;
+                    while i < tag.end
+                        && !matches!(bytes[i], b'>')
+                        && !bytes[i].is_ascii_whitespace()
+                    {
+                        i += 1;
+                    }
+                    value = start..i;
+                }
+            }
+        }
+
+        if &svg[name_start..name_end] == name {
+            return Some((attribute_start..i, value));
+        }
+    }
+    None
+}
+
+/// Adds a class to the root `<svg>` tag, keeping any classes it already has.
+fn add_root_class(svg: &mut String, class: &str) {
+    match root_attribute(svg, "class") {
+        Some((_, value)) if value.is_empty() => svg.replace_range(value, class),
+        Some((_, value)) => svg.insert_str(value.end, &format!(" {class}")),
+        None => {
+            if let Some(tag) = root_tag(svg) {
+                svg.insert_str(tag.start + "<svg".len(), &format!(r#" class="{class}""#));
+            }
+        }
+    }
+}
+
+/// Removes every `<style>` element from an SVG and returns their combined CSS.
+fn take_styles(svg: &mut String) -> String {
+    let mut css = String::new();
+    let mut search_from = 0;
+
+    while let Some(found) = svg[search_from..].find("<style") {
+        let start = search_from + found;
+        let after_name = svg[start + "<style".len()..].chars().next();
+        if !matches!(after_name, Some(c) if c == '>' || c == '/' || c.is_whitespace()) {
+            search_from = start + "<style".len();
+            continue;
+        }
+        let Some(tag_end) = svg[start..].find('>').map(|i| start + i + 1) else {
+            break;
+        };
+        if svg[..tag_end].ends_with("/>") {
+            svg.replace_range(start..tag_end, "");
+            search_from = start;
+            continue;
+        }
+        let Some(close) = svg[tag_end..].find("</style>").map(|i| tag_end + i) else {
+            break;
+        };
+
+        css.push_str(&decode_text(&svg[tag_end..close]));
+        css.push('\n');
+        svg.replace_range(start..close + "</style>".len(), "");
+        search_from = start;
+    }
+
+    css
+}
+
+/// Decodes the text content of an XML element, which is either a CDATA section or entity-escaped text.
+fn decode_text(text: &str) -> String {
+    let trimmed = text.trim();
+    if let Some(cdata) = trimmed
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+    {
+        return cdata.to_string();
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Scopes every rule in a stylesheet to elements inside `scope`, so it only applies to one diagram.
+///
+/// Selectors that target the root `<svg>` itself (by the `svg` type, `:root`, or the root's id)
+/// have the scope attached to them instead of prefixed. Rules inside `@media`, `@supports`, `@container`,
+/// and `@layer` blocks are scoped recursively; other at-rules like `@keyframes` and `@font-face` are kept as-is.
+fn scope_css(css: &str, scope: &str, root_id: Option<&str>) -> String {
+    let css = strip_comments(css);
+    let mut out = String::new();
+    let mut rest = css.as_str();
+
+    loop {
+        rest = rest.trim_start();
+        let Some(i) = rest.find(['{', ';']) else {
+            break;
+        };
+        let prelude = rest[..i].trim();
+
+        if rest.as_bytes()[i] == b';' {
+            out.push_str(&format!("{prelude};\n"));
+            rest = &rest[i + 1..];
+            continue;
+        }
+
+        let close = matching_brace(rest, i);
+        let block = &rest[i + 1..close];
+        if let Some(at_rule) = prelude.strip_prefix('@') {
+            let name = at_rule
+                .split(|c: char| !c.is_alphanumeric() && c != '-')
+                .next();
+            if matches!(name, Some("media" | "supports" | "container" | "layer")) {
+                out.push_str(&format!(
+                    "{prelude} {{\n{}}}\n",
+                    scope_css(block, scope, root_id)
+                ));
+            } else {
+                out.push_str(&format!("{prelude} {{{block}}}\n"));
+            }
+        } else {
+            let selectors = split_top_level(prelude, ',')
+                .into_iter()
+                .map(|selector| scope_selector(selector.trim(), scope, root_id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            out.push_str(&format!("{selectors} {{{}}}\n", block.trim()));
+        }
+
+        rest = rest.get(close + 1..).unwrap_or_default();
+    }
+
+    out
+}
+
+fn scope_selector(selector: &str, scope: &str, root_id: Option<&str>) -> String {
+    let mut depth = 0;
+    let mut first_end = selector.len();
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '>' | '+' | '~' if depth == 0 => {
+                first_end = i;
+                break;
+            }
+            c if c.is_whitespace() && depth == 0 => {
+                first_end = i;
+                break;
+            }
+            _ => {}
+        }
+    }
+    let (first, rest) = selector.split_at(first_end);
+
+    if let Some(after) = first.strip_prefix(":root") {
+        return format!("{scope}{after}{rest}");
+    }
+    if let Some(after) = first.strip_prefix("svg") {
+        if !after.starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_') {
+            return format!("{scope}{after}{rest}");
+        }
+    }
+    let targets_root = root_id.is_some_and(|id| {
+        first.match_indices(&format!("#{id}")).any(|(i, m)| {
+            !first[i + m.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_')
+        })
+    });
+    if targets_root {
+        return format!("{scope}{selector}");
+    }
+
+    format!("{scope} {selector}")
+}
+
+/// Index of the brace that closes the block opened at `open`, or the end of the string if it's unclosed.
+fn matching_brace(css: &str, open: usize) -> usize {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in css[open..].char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return open + i;
+                }
+            }
+            _ => {}
+        }
+    }
+    css.len()
+}
+
+/// Splits on a separator, ignoring separators inside parentheses, brackets, and strings.
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagram::extract_svg;
+
+    const DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+
+    #[test]
+    fn file_from_response_with_prolog_has_one_declaration_and_no_doctype() {
+        let response = concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n",
+            "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" ",
+            "\"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\">\n",
+            "<!-- Generated by graphviz -->\n",
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 10 10\"><g id=\"a\"/></svg>\n",
+        );
+        let svg = extract_svg(response).unwrap();
+        assert_eq!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 10 10\"><g id=\"a\"/></svg>"
+        );
+
+        let file = as_file(&svg);
+        assert!(file.starts_with(&format!("{DECLARATION}\n<svg")));
+        assert_eq!(file.matches("<?xml").count(), 1);
+        assert!(!file.contains("<!DOCTYPE"));
+        assert!(file.ends_with("</svg>\n"));
+        xmltree::Element::parse(file.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn file_from_bare_svg_gets_a_declaration() {
+        let response = "<svg xmlns=\"http://www.w3.org/2000/svg\"><rect/></svg>";
+        let svg = extract_svg(response).unwrap();
+        assert_eq!(svg, response);
+        assert_eq!(as_file(&svg), format!("{DECLARATION}\n{response}\n"));
+    }
+
+    #[test]
+    fn links_in_plantuml_svgs_survive_id_prefixes_and_responsive_sizing() {
+        let svg = concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" "#,
+            r#"contentStyleType="text/css" height="120px" preserveAspectRatio="none" "#,
+            r#"style="width:200px;height:120px;" version="1.1" viewBox="0 0 200 120" width="200px" "#,
+            r#"zoomAndPan="magnify"><defs><filter id="f1"/></defs><g>"#,
+            r#"<a href="https://example.com/docs" target="_top" title="Read the docs" "#,
+            r#"xlink:actuate="onRequest" xlink:href="https://example.com/docs" xlink:show="new" "#,
+            r#"xlink:title="Read the docs" xlink:type="simple">"#,
+            r#"<rect filter="url(#f1)" id="elem_A" height="40" width="80"/></a></g></svg>"#,
+        );
+
+        let mut svg = namespace_ids(svg, "kroki-0-");
+        make_responsive(&mut svg);
+
+        for attribute in [
+            r#"href="https://example.com/docs""#,
+            r#"xlink:href="https://example.com/docs""#,
+            r#"target="_top""#,
+            r#"title="Read the docs""#,
+            r#"xlink:title="Read the docs""#,
+            r#"xlink:show="new""#,
+        ] {
+            assert!(svg.contains(attribute), "lost {attribute} in {svg}");
+        }
+        assert!(svg.contains(r#"id="kroki-0-f1""#));
+        assert!(svg.contains(r#"filter="url(#kroki-0-f1)""#));
+        assert!(svg.contains(r#"id="kroki-0-elem_A""#));
+        let root = xmltree::Element::parse(svg.as_bytes()).unwrap();
+        assert!(!root.attributes.contains_key("width"));
+        assert!(!root.attributes.contains_key("height"));
+        let style = root.attributes.get("style").map_or("", String::as_str);
+        assert!(
+            !style.contains("width") && !style.contains("height"),
+            "fixed size left in style=\"{style}\""
+        );
+        assert_eq!(root.attributes["viewBox"], "0 0 200 120");
+        // Sizes inside the diagram aren't the root's.
+        assert!(svg.contains(r#"height="40" width="80""#));
+    }
+
+    #[test]
+    fn responsive_svgs_lose_sizes_in_their_style() {
+        let mut svg = concat!(
+            r#"<svg style="width:200px;height:120px;background:#FFFFFF;" viewBox="0 0 200 120" "#,
+            r#"width="200px"><rect style="width:10px"/></svg>"#,
+        )
+        .to_string();
+        make_responsive(&mut svg);
+        assert_eq!(
+            svg,
+            r#"<svg style="background:#FFFFFF;" viewBox="0 0 200 120"><rect style="width:10px"/></svg>"#
+        );
+
+        let mut svg =
+            r#"<svg style="width: 200px; height: 120px" viewBox="0 0 200 120"></svg>"#.to_string();
+        make_responsive(&mut svg);
+        assert_eq!(svg, r#"<svg viewBox="0 0 200 120"></svg>"#);
+    }
+}
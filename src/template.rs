@@ -0,0 +1,26 @@
+use anyhow::{bail, Context, Result};
+use handlebars::Handlebars;
+use std::path::Path;
+
+/// Renders a handlebars template into diagram source, with data from a JSON or TOML file.
+///
+/// Output isn't HTML-escaped, since it's diagram code rather than markup.
+pub fn render(template: &str, data_path: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(data_path)
+        .with_context(|| format!("could not read {}", data_path.display()))?;
+    let data: serde_json::Value = match data_path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&text)
+            .with_context(|| format!("could not parse {}", data_path.display()))?,
+        Some("toml") => toml::from_str(&text)
+            .with_context(|| format!("could not parse {}", data_path.display()))?,
+        _ => bail!(
+            "unsupported data file {}: expected a .json or .toml file",
+            data_path.display()
+        ),
+    };
+
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars.register_escape_fn(handlebars::no_escape);
+    Ok(handlebars.render_template(template, &data)?)
+}
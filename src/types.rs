@@ -0,0 +1,62 @@
+/// Diagram types kroki supports, so typos can be caught before anything is sent.
+pub const KNOWN_TYPES: &[&str] = &[
+    "actdiag",
+    "blockdiag",
+    "bpmn",
+    "bytefield",
+    "c4plantuml",
+    "d2",
+    "dbml",
+    "ditaa",
+    "dot",
+    "erd",
+    "excalidraw",
+    "graphviz",
+    "mermaid",
+    "nomnoml",
+    "nwdiag",
+    "packetdiag",
+    "pikchr",
+    "plantuml",
+    "rackdiag",
+    "seqdiag",
+    "structurizr",
+    "svgbob",
+    "symbolator",
+    "tikz",
+    "umlet",
+    "vega",
+    "vegalite",
+    "wavedrom",
+    "wireviz",
+];
+
+/// Whether kroki supports a diagram type.
+pub fn is_known(diagram_type: &str) -> bool {
+    KNOWN_TYPES.contains(&diagram_type)
+}
+
+/// The known type closest to an unknown one, if it's close enough to probably be a typo of it.
+pub fn closest(diagram_type: &str) -> Option<&'static str> {
+    KNOWN_TYPES
+        .iter()
+        .map(|known| (edit_distance(diagram_type, known), *known))
+        .filter(|(distance, _)| *distance <= (diagram_type.chars().count() / 3).max(1))
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// The number of single-character insertions, deletions, and substitutions that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}